@@ -102,6 +102,24 @@
                 description = "Reinitialize whisper state before every transcription (slower but avoids stale state)";
               };
 
+              rateLimit = lib.mkOption {
+                type = lib.types.nullOr lib.types.ints.positive;
+                default = null;
+                description = "Max new connections per minute per client IP (null = unlimited)";
+              };
+
+              rateLimitBurst = lib.mkOption {
+                type = lib.types.ints.positive;
+                default = 5;
+                description = "Connections per client IP allowed in a burst when rateLimit is set";
+              };
+
+              configureTimeout = lib.mkOption {
+                type = lib.types.nullOr lib.types.ints.positive;
+                default = null;
+                description = "Seconds a client has to send Configure after connecting (null = unlimited)";
+              };
+
               enableGpu = lib.mkOption {
                 type = lib.types.bool;
                 default = false;
@@ -186,6 +204,13 @@
                     ]
                     ++ lib.optionals instanceCfg.reinitState [
                       "--reinit-state"
+                    ]
+                    ++ lib.optionals (instanceCfg.rateLimit != null) [
+                      "--rate-limit" (toString instanceCfg.rateLimit)
+                      "--rate-limit-burst" (toString instanceCfg.rateLimitBurst)
+                    ]
+                    ++ lib.optionals (instanceCfg.configureTimeout != null) [
+                      "--configure-timeout-secs" (toString instanceCfg.configureTimeout)
                     ];
                   in lib.escapeShellArgs args;
 
@@ -4,28 +4,109 @@ pub const FRAME_SIZE_CS: u32 = 6; // 2*30ms = 60ms (common Opus frame size)
 pub const SAMPLE_RATE: u32 = 16000; // Whisper requires 16kHz
 pub const CS_SAMPLES: u32 = SAMPLE_RATE / 100; // 160 = 1 cs at 16kHz
 pub const FRAME_SIZE_SAMPLES: u32 = FRAME_SIZE_CS * CS_SAMPLES; // 960
+pub const MAX_RELIABLE_SEGMENT_CS: i64 = 3000; // whisper's 30s window
+pub const SEQ_MATCH_CS: i64 = 50; // see Segment::seq
+pub const UNCERTAINTY_TOKENS: usize = 5; // see ServerMessage::Uncertainty
+pub const LOW_CONFIDENCE: f32 = 0.5; // FinalSummary threshold
+pub const PROTOCOL_VERSION: u32 = 1; // the only one so far
+/// With `compression: "zstd"`, server messages may arrive as binary frames:
+/// this byte followed by the zstd-compressed JSON of the message. Messages
+/// can still arrive as plain text frames too, clients must handle both.
+pub const ZSTD_FRAME_TAG: u8 = 0x01;
 
+/// Unknown fields are ignored, unless the server runs with --strict-config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    pub token: Option<String>, // optional auth token
+    pub protocol_version: Option<u32>, // rejected if unsupported
+    pub language: Option<String>, // defaults to "auto"
+    pub context: Option<String>, // extra context for transcription
+    pub max_len: Option<i32>,  // max segment length in chars (0 = unlimited)
+    pub max_tokens: Option<i32>, // max tokens per segment (0 = unlimited)
+    pub single_segment: Option<bool>, // force single segment output
+    pub max_initial_ts: Option<f32>, // max timestamp for first segment start (seconds)
+    pub no_preview: Option<bool>,    // only transcribe after EndOfStream
+    pub two_stroke: Option<bool>, // server-side verification of second-to-last segment
+    pub stats: Option<bool>,      // send Stats after every transcription
+    pub dtx: Option<bool>, // audio frames are prefixed with a u32 LE frame index
+    pub interim_words: Option<bool>, // send WordsAppended with new words
+    pub language_probabilities: Option<usize>, // send top-K detected languages
+    pub long_form: Option<bool>, // finalize in stitched 30s windows
+    pub final_summary: Option<bool>, // send FinalSummary after finalization
+    pub min_speech: Option<bool>, // require 180ms of speech, not of audio
+    pub drop_low_quality_final: Option<bool>, // drop a dubious last segment
+    pub audio_echo: Option<bool>, // send decoded s16le PCM back (256 kbit/s)
+    pub disagreement_margin: Option<f32>, // see vad_whisper_disagreement
+    pub temperature: Option<f32>, // initial decode temperature, 0..1
+    pub config_ack: Option<bool>, // send ConfigAck (default: true)
+    pub formatted_timecodes: Option<bool>, // fill Segment start_tc/end_tc
+    pub auto_gain_dbfs: Option<f32>, // normalize speech toward this level
+    pub auto_gain_smoothing: Option<f32>, // gain step per frame, 0..1
+    pub adaptive_quality: Option<bool>, // degrade when falling behind
+    pub segment_seq: Option<bool>, // number complete segments, see Segment
+    pub session_export: Option<bool>, // send SessionExport after finalization
+    pub uncertainty: Option<bool>, // send Uncertainty with every preview
+    // [token ID, bias] pairs added to the logits of sampled tokens,
+    // the prompt and context are not affected
+    pub logit_bias: Option<Vec<(i32, f32)>>,
+    pub granularity: Option<String>, // word, phrase, sentence or paragraph
+    pub fast_preview: Option<bool>,  // send a quick tail-only preview first
+    pub compression: Option<String>, // "zstd", see ZSTD_FRAME_TAG
+    pub trim_leading_silence: Option<bool>, // skip silence before speech
+    // "opus" (default) or "pcm_s16le", binary frames of 16kHz mono samples
+    // of any length, a sample split across frames is reassembled
+    pub audio_format: Option<String>,
+    pub model: Option<String>, // file name as in Hello, defaults to --model
+    // "transcribe" (default) or "translate" into English, language still
+    // names the spoken one
+    pub task: Option<String>,
+}
+
+/// Messages and binary audio frames are applied in the order they are sent,
+/// so control messages take effect at their position in the audio stream.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    Configure {
-        // sent once after connection,required
-        token: Option<String>,        // optional auth token
-        language: Option<String>,     // defaults to "auto"
-        context: Option<String>,      // extra context for transcription
-        max_len: Option<i32>, // max segment length in chars (0 = unlimited)
-        max_tokens: Option<i32>, // max tokens per segment (0 = unlimited)
-        single_segment: Option<bool>, // force single segment output
-        max_initial_ts: Option<f32>, // max timestamp for first segment start (seconds)
-        no_preview: Option<bool>, // only transcribe after EndOfStream
-        two_stroke: Option<bool>, // server-side verification of second-to-last segment
-    },
+    Configure(Config), // sent once after connection, required
     // no explicit AudioChunk message - binary frames are implicitly audio
     Advance {
         timestamp_cs: i64, // forget audio before this, centiseconds from 0
         context: Option<Segment>, // last confirmed segment, for token IDs
     },
-    EndOfStream, // trigger final transcription
+    // trigger final transcription; Advances and audio sent before it are
+    // all applied first, anything sent after it is ignored
+    EndOfStream,
+    GetState, // request an AdvanceState
+    // audio frames received while paused are dropped rather than
+    // transcribed, the prompt context and advance_cs carry over to Resume
+    Pause,
+    Resume,
+    PacketLost {
+        // Opus packets lost before the next binary frame, concealed with
+        // that frame's in-band FEC and PLC instead of leaving a gap; with
+        // dtx, the lost packets are among the indices skipped
+        count: u32,
+    },
+    SpeakerHints {
+        // diarization from an external source, tokens whose midpoint falls
+        // in a range get its speaker; later hints take precedence
+        ranges: Vec<(i64, i64, i32)>, // (start_cs, end_cs, speaker_id)
+    },
+    Ping {
+        // keepalive for clients that can't send WebSocket pings, answered
+        // with a Pong carrying the same nonce
+        nonce: u64,
+    },
+    Reconfigure {
+        // applied from the next transcription on, audio is kept
+        language: Option<String>, // "auto" to switch to auto-detection
+        context: Option<String>,  // replaces the Configure context
+    },
+    ReloadModel {
+        // sent instead of Configure, reloads the model file for new sessions
+        token: Option<String>, // required, must match the API token
+        model: Option<String>, // file name as in Hello, defaults to --model
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +117,8 @@ pub struct Token {
     pub start_cs: i64,
     pub end_cs: i64,
     pub probability: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker_id: Option<i32>, // from SpeakerHints covering its midpoint
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,14 +127,164 @@ pub struct Segment {
     pub start_cs: i64,
     pub end_cs: i64,
     pub tokens: Vec<Token>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub words: Vec<Word>, // the non-special tokens grouped by tokens_to_words
+    // whisper decoded no timestamp tokens, segment boundaries are guesses
     pub fallback_segmentation: bool,
     pub end_vad_probability: f32,
     pub no_speech_probability: f32,
+    #[serde(default)]
+    pub avg_logprob: f32, // mean log probability of the text tokens, 0 if none
+    #[serde(default)]
+    pub is_final: bool, // won't change with more audio
+    #[serde(default)]
+    pub vad_whisper_disagreement: bool, // VAD and no_speech diverge
+    // looping text, or no speech by both whisper and the VAD: likely made
+    // up by whisper on silence or music
+    #[serde(default)]
+    pub hallucinated: bool,
+    // whisper decodes 30s windows, timestamps of longer segments are guesses
+    #[serde(default)]
+    pub timestamp_unreliable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_tc: Option<String>, // HH:MM:SS.mmm, with formatted_timecodes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_tc: Option<String>,
+    // with segment_seq, complete segments are numbered in order of first
+    // appearance; a segment starting within SEQ_MATCH_CS of an already
+    // numbered one keeps its number across retranscriptions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speaker_id: Option<i32>, // see majority_speaker
+}
+
+impl Segment {
+    /// Mean probability of the non-special tokens. Special tokens carry
+    /// probabilities too, but they say nothing about the recognized text,
+    /// so they are never part of confidence metrics. None without text.
+    pub fn confidence(&self) -> Option<f32> {
+        let probabilities: Vec<f32> = self
+            .tokens
+            .iter()
+            .filter(|t| !t.special)
+            .map(|t| t.probability)
+            .collect();
+        if probabilities.is_empty() {
+            return None;
+        }
+        Some(probabilities.iter().sum::<f32>() / probabilities.len() as f32)
+    }
+}
+
+/// Formats centiseconds as HH:MM:SS.mmm, hours are not wrapped.
+pub fn format_timecode(cs: i64) -> String {
+    let cs = cs.max(0);
+    let (h, m, s) = (cs / 360000, cs / 6000 % 60, cs / 100 % 60);
+    format!("{:02}:{:02}:{:02}.{:03}", h, m, s, cs % 100 * 10)
+}
+
+/// Whether a segment spanning start_cs..end_cs outlasts whisper's window,
+/// so that its timestamps are guesses; see Segment::timestamp_unreliable.
+pub fn timestamp_unreliable(start_cs: i64, end_cs: i64) -> bool {
+    end_cs - start_cs > MAX_RELIABLE_SEGMENT_CS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Word {
+    pub text: String,
+    pub start_cs: i64,
+    pub end_cs: i64,
+    pub probability: f32, // mean of the constituent token probabilities
+}
+
+/// The speaker of most of the non-special tokens, ties going to the one
+/// heard first. None if no token has a speaker.
+pub fn majority_speaker(tokens: &[Token]) -> Option<i32> {
+    let mut counts: Vec<(i32, usize)> = Vec::new();
+    let speakers = tokens.iter().filter(|t| !t.special);
+    for speaker in speakers.filter_map(|t| t.speaker_id) {
+        match counts.iter_mut().find(|(s, _)| *s == speaker) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((speaker, 1)),
+        }
+    }
+    // max_by_key would return the last of equal counts
+    counts
+        .into_iter()
+        .min_by_key(|&(_, n)| std::cmp::Reverse(n))
+        .map(|(s, _)| s)
+}
+
+/// Groups non-special tokens into words, starting a new word on every token
+/// that begins with a space; the first token starts one regardless.
+/// Punctuation is never a word of its own: it joins the word before it, or
+/// the word after it when it opens the text. Word probabilities are the
+/// mean of their tokens'.
+pub fn tokens_to_words(tokens: &[Token]) -> Vec<Word> {
+    // with the number of tokens, whose probabilities are summed until the end
+    let mut groups: Vec<(Word, usize)> = Vec::new();
+    for token in tokens.iter().filter(|t| !t.special) {
+        match groups.last_mut() {
+            Some((word, n)) if !token.text.starts_with(' ') => {
+                word.text.push_str(&token.text);
+                word.end_cs = token.end_cs;
+                word.probability += token.probability;
+                *n += 1;
+            }
+            _ => groups.push((
+                Word {
+                    text: token.text.clone(),
+                    start_cs: token.start_cs,
+                    end_cs: token.end_cs,
+                    probability: token.probability,
+                },
+                1,
+            )),
+        }
+    }
+
+    let mut words: Vec<(Word, usize)> = Vec::new();
+    let mut opening: Option<(Word, usize)> = None; // punctuation before any
+    for (mut word, mut n) in groups {
+        if let Some((open, m)) = opening.take() {
+            word.text = format!("{}{}", open.text.trim_end(), word.text.trim());
+            word.start_cs = open.start_cs;
+            word.probability += open.probability;
+            n += m;
+        }
+        let punctuation = word.text.chars().all(|c| !c.is_alphanumeric());
+        match words.last_mut() {
+            Some((last, m)) if punctuation => {
+                last.text.push_str(word.text.trim());
+                last.end_cs = word.end_cs;
+                last.probability += word.probability;
+                *m += n;
+            }
+            None if punctuation => opening = Some((word, n)),
+            _ => words.push((word, n)),
+        }
+    }
+    words.extend(opening); // the text is nothing but punctuation
+    words
+        .into_iter()
+        .map(|(mut word, n)| {
+            word.text = word.text.trim().to_string();
+            word.probability /= n as f32;
+            word
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
+    Hello {
+        // sent right after a successful Configure
+        protocol_version: u32,
+        supported_languages: Vec<String>,
+        model_name: String, // model file name
+    },
     Transcription {
         complete: Vec<Segment>, // segments considered complete
         incomplete: Option<Segment>, // still-growing preview
@@ -59,14 +292,170 @@ pub enum ServerMessage {
         advance_cs: i64, // beginning timestamp of the transcription result
     },
     AdvanceSuggestion {
-        advance_cs: i64,               // matches the Transcription this belongs to
-        timestamp_cs: i64,             // suggested advance point
-        segments: Vec<Segment>,        // re-transcription results
+        advance_cs: i64,   // matches the Transcription this belongs to
+        timestamp_cs: i64, // suggested advance point
+        segments: Vec<Segment>, // re-transcription results
         original_last_segment: Segment, // last segment from the original transcription
-        exact_match: bool,             // whether the first re-transcribed segment matches exactly
-        n_matching_tokens: usize,      // number of leading non-special tokens that match
+        exact_match: bool, // whether the first re-transcribed segment matches exactly
+        n_matching_tokens: usize, // number of leading non-special tokens that match
+    },
+    WordsAppended {
+        words: Vec<Word>, // words that appeared since the last WordsAppended
+    },
+    LanguageProbabilities {
+        ranked: Vec<(String, f32)>, // most probable language first
+    },
+    FinalSummary {
+        // over the whole session: the segments confirmed by Advance, then
+        // the final ones
+        total_segments: usize,
+        mean_confidence: f32, // mean over segments with text
+        low_confidence_segments: usize, // segments below LOW_CONFIDENCE
+        total_duration_cs: i64, // from first segment start to last end
+    },
+    Uncertainty {
+        // mean probability of the last UNCERTAINTY_TOKENS text tokens of
+        // the incomplete segment, low values mean the model is unsure
+        value: f32,
+    },
+    SessionExport {
+        // all segments confirmed by Advance, then the final ones
+        segments: Vec<Segment>,
+        language: Option<String>, // configured or detected, None if unknown
+        duration_cs: i64,         // of all audio received
+        model: String,            // model file name
+    },
+    AdvanceState {
+        advance_cs: i64,           // audio before this has been forgotten
+        buffer_cs: i64,            // length of the buffered audio
+        transcribed_up_to_cs: i64, // end of the last transcription
+    },
+    Stats {
+        advance_cs: i64, // matches the Transcription this belongs to
+        audio_ctx: i32,  // audio context actually passed to whisper
+    },
+    QualityChanged {
+        degraded: bool, // greedy sampling and reduced audio_ctx if true
+        realtime_factor: f64, // of the transcription that triggered it
+    },
+    ConfigAck {
+        applied: serde_json::Value, // effective Config, defaults filled in
+    },
+    ModelReloaded {
+        model: String, // file name of the reloaded model, as in Hello
+    },
+    Pong {
+        nonce: u64, // of the Ping this answers
     },
     Error {
-        message: String,
+        message: String, // for humans
+        // stable machine-readable reason, e.g. AUTH_FAILED, BAD_CONFIGURE,
+        // PROTOCOL_ERROR, DECODE_ERROR, RATE_LIMITED, CONFIGURE_TIMEOUT
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_without_is_final_are_not_final() {
+        let json = r#"{"text": " Hi", "start_cs": 0, "end_cs": 40,
+            "tokens": [], "fallback_segmentation": false,
+            "end_vad_probability": 0.9, "no_speech_probability": 0.1}"#;
+        let segment: Segment = serde_json::from_str(json).unwrap();
+        assert!(!segment.is_final);
+        assert_eq!(segment.seq, None);
+    }
+
+    #[test]
+    fn advance_state_answers_get_state() {
+        let get_state = serde_json::from_str(r#"{"type": "GetState"}"#);
+        assert!(matches!(get_state, Ok(ClientMessage::GetState)));
+        let state = ServerMessage::AdvanceState {
+            advance_cs: 100,
+            buffer_cs: 250,
+            transcribed_up_to_cs: 300,
+        };
+        let json: serde_json::Value = serde_json::to_value(&state).unwrap();
+        assert_eq!(json["type"], "AdvanceState");
+        assert_eq!(json["transcribed_up_to_cs"], 300);
+    }
+
+    #[test]
+    fn confidence_ignores_special_tokens() {
+        let token = |special, probability| Token {
+            text: String::new(),
+            id: 0,
+            special,
+            start_cs: 0,
+            end_cs: 0,
+            probability,
+            speaker_id: None,
+        };
+        let json = r#"{"text": "", "start_cs": 0, "end_cs": 40,
+            "tokens": [], "fallback_segmentation": false,
+            "end_vad_probability": 0.9, "no_speech_probability": 0.1}"#;
+        let mut segment: Segment = serde_json::from_str(json).unwrap();
+        assert_eq!(segment.confidence(), None);
+        segment.tokens = vec![token(true, 1.0), token(true, 0.9)];
+        assert_eq!(segment.confidence(), None);
+        segment
+            .tokens
+            .extend([token(false, 0.4), token(false, 0.6)]);
+        assert_eq!(segment.confidence(), Some(0.5));
+    }
+
+    #[test]
+    fn only_segments_beyond_the_window_are_unreliable() {
+        assert!(!timestamp_unreliable(100, 100 + MAX_RELIABLE_SEGMENT_CS));
+        assert!(timestamp_unreliable(100, 101 + MAX_RELIABLE_SEGMENT_CS));
+        assert!(!timestamp_unreliable(500, 400));
+    }
+
+    #[test]
+    fn configure_is_acknowledged_by_default() {
+        let json = r#"{"type": "Configure", "language": "en"}"#;
+        let Ok(ClientMessage::Configure(config)) = serde_json::from_str(json)
+        else {
+            panic!("Configure not parsed");
+        };
+        assert_eq!(config.config_ack, None);
+        let applied = serde_json::to_value(Config {
+            language: config.language,
+            config_ack: Some(true),
+            ..Config::default()
+        })
+        .unwrap();
+        let ack = ServerMessage::ConfigAck { applied };
+        let json: serde_json::Value = serde_json::to_value(&ack).unwrap();
+        assert_eq!(json["type"], "ConfigAck");
+        assert_eq!(json["applied"]["language"], "en");
+    }
+
+    #[test]
+    fn timecodes_are_formatted_to_the_millisecond() {
+        assert_eq!(format_timecode(0), "00:00:00.000");
+        assert_eq!(format_timecode(6123), "00:01:01.230");
+        assert_eq!(format_timecode(100 * 360000 + 5), "100:00:00.050");
+        assert_eq!(format_timecode(-20), "00:00:00.000");
+    }
+
+    #[test]
+    fn errors_carry_an_optional_code() {
+        let error = ServerMessage::Error {
+            message: "invalid token".into(),
+            code: Some("AUTH_FAILED".into()),
+        };
+        let json: serde_json::Value = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], "AUTH_FAILED");
+        let old = r#"{"type": "Error", "message": "invalid token"}"#;
+        let parsed = serde_json::from_str(old);
+        assert!(matches!(
+            parsed,
+            Ok(ServerMessage::Error { code: None, .. })
+        ));
+    }
+}
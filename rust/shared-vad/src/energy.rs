@@ -0,0 +1,39 @@
+use crate::VadBackend;
+
+const FRAME: usize = 256; // same framing as earshot
+const MIN_NOISE_FLOOR: f32 = 1e-4; // about -80 dBFS, keeps the SNR finite
+const NOISE_ALPHA: f32 = 0.05; // EMA weight of a new low-energy frame
+const LOW_ENERGY_DB: f32 = 6.; // frames this close to the floor are noise
+const SNR_MID_DB: f32 = 12.; // SNR mapped to probability 0.5
+const SNR_SCALE_DB: f32 = 3.; // steepness of the mapping
+
+/// Pure-Rust detector for builds that can't link earshot, the default one
+/// with the vad-energy feature. Scores frames by
+/// their RMS above a noise floor that follows the low-energy frames.
+#[derive(Default)]
+pub struct EnergyDetector {
+    noise_floor: Option<f32>, // linear RMS, None before the first frame
+}
+
+impl VadBackend for EnergyDetector {
+    fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+        let sum: f32 = samples.iter().map(|&s| (s as f32).powi(2)).sum();
+        let rms = ((sum / samples.len().max(1) as f32).sqrt() / 32768.)
+            .max(MIN_NOISE_FLOOR);
+        let floor = *self.noise_floor.get_or_insert(rms);
+        let snr_db = 20. * (rms / floor).log10();
+        if snr_db < LOW_ENERGY_DB {
+            let adapted = floor + (rms - floor) * NOISE_ALPHA;
+            self.noise_floor = Some(adapted.max(MIN_NOISE_FLOOR));
+        }
+        1. / (1. + (-(snr_db - SNR_MID_DB) / SNR_SCALE_DB).exp())
+    }
+
+    fn frame_len(&self) -> usize {
+        FRAME
+    }
+
+    fn reset(&mut self) {
+        self.noise_floor = None;
+    }
+}
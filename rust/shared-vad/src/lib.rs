@@ -1,12 +1,119 @@
+#[cfg(feature = "earshot")]
 use earshot::Detector;
 
+use serde::{Deserialize, Serialize};
+
+mod resample;
+use resample::Resampler;
+
+#[cfg(feature = "vad-energy")]
+mod energy;
+#[cfg(feature = "vad-energy")]
+pub use energy::EnergyDetector;
+
+#[cfg(not(any(feature = "earshot", feature = "vad-energy")))]
+compile_error!("shared-vad needs either the earshot or vad-energy feature");
+
+#[cfg(feature = "earshot")]
 const EARSHOT_FRAME: usize = 256; // 16ms at 16kHz
-const EARSHOT_MS: usize = 16;
+const SAMPLE_RATE: u32 = 16000; // of the audio the backends score
+const CS_SAMPLES: usize = 160; // 1 cs at 16kHz
+
+/// Probability recorded for frames that were never delivered.
+pub const GAP_PROBABILITY: f32 = 0.0;
+const DEFAULT_THRESHOLD: f32 = 0.5;
+
+/// A speech detector scoring fixed-size frames of 16kHz audio.
+/// `Vad` takes care of framing the incoming samples.
+pub trait VadBackend {
+    /// Speech probability of exactly `frame_len()` samples.
+    fn predict_frame(&mut self, samples: &[i16]) -> f32;
+    fn frame_len(&self) -> usize;
+    /// Forget any state carried over from previous frames.
+    fn reset(&mut self) {}
+}
+
+#[cfg(feature = "earshot")]
+impl VadBackend for Detector {
+    fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+        self.predict_i16(samples)
+    }
+
+    fn frame_len(&self) -> usize {
+        EARSHOT_FRAME
+    }
+
+    fn reset(&mut self) {
+        *self = Detector::default();
+    }
+}
+
+/// Debounced transitions reported by `Vad::speech_events`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeechEvent {
+    SpeechStart { cs: i64 },
+    SpeechEnd { cs: i64 },
+}
+
+/// Kept before the detected speech onset so that the first word isn't
+/// clipped, see `trim_leading_silence`.
+const PRE_ROLL_SAMPLES: usize = 1600; // 100ms
+
+/// Offset of the first speech onset in `samples`, the audio `vad` consumed,
+/// minus a 100ms pre-roll. 0 if no frame reaches `threshold`.
+pub fn trim_leading_silence(
+    samples: &[i16],
+    vad: &Vad,
+    threshold: f32,
+) -> usize {
+    let Some(onset) = vad.probabilities.iter().position(|&p| p >= threshold)
+    else {
+        return 0;
+    };
+    let onset_sample =
+        (onset * vad.backend.frame_len()).saturating_sub(vad.origin);
+    onset_sample
+        .saturating_sub(PRE_ROLL_SAMPLES)
+        .min(samples.len())
+}
+
+// chosen at compile time, so that callers of Vad::new don't change
+#[cfg(all(feature = "earshot", not(feature = "vad-energy")))]
+fn default_backend() -> Box<dyn VadBackend + Send> {
+    Box::new(Detector::default())
+}
+
+#[cfg(feature = "vad-energy")]
+fn default_backend() -> Box<dyn VadBackend + Send> {
+    Box::new(EnergyDetector::default())
+}
+
+/// The history of a `Vad`, for checkpointing sessions. The backend's own
+/// state is not part of it and starts from scratch on import. earshot
+/// carries little state across frames, so only the first frames consumed
+/// after an import may score slightly differently than without one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VadState {
+    pub probabilities: Vec<f32>,
+    pub leftovers: Vec<i16>,
+    #[serde(default)]
+    pub origin: usize,
+    #[serde(default)]
+    pub padded: bool,
+}
 
 pub struct Vad {
-    detector: Detector,
-    probabilities: Vec<f32>, // for earshot-native 16ms chunks
-    leftovers: Vec<i16>,     // samples not yet divisible by 16ms
+    backend: Box<dyn VadBackend + Send>,
+    probabilities: Vec<f32>,      // one per backend frame
+    leftovers: Vec<i16>,          // samples not yet filling a frame
+    origin: usize, // samples of frame 0 before cs 0, see drop_front
+    padded: bool,  // the last frame was zero-padded by flush
+    enter_threshold: f32, // probability at which speech starts
+    exit_threshold: f32, // probability below which speech ends
+    min_gap_cs: i64, // shorter gaps between speech_spans are merged
+    min_speech_cs: i64, // dwell before a SpeechStart is confirmed
+    min_silence_cs: i64, // dwell before a SpeechEnd is confirmed
+    resampler: Option<Resampler>, // for input not at 16kHz
 }
 
 impl Default for Vad {
@@ -17,39 +124,242 @@ impl Default for Vad {
 
 impl Vad {
     pub fn new() -> Self {
+        Self::with_threshold(DEFAULT_THRESHOLD)
+    }
+
+    /// A VAD considering frames at or above `speech_prob` speech.
+    /// Raise it to make detection less eager in noisy audio.
+    pub fn with_threshold(speech_prob: f32) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&speech_prob),
+            "speech threshold must be within [0, 1]"
+        );
+        let mut vad = Self::with_backend(default_backend());
+        vad.enter_threshold = speech_prob;
+        vad.exit_threshold = speech_prob;
+        vad
+    }
+
+    /// A VAD using another detector than the default one.
+    pub fn with_backend(backend: Box<dyn VadBackend + Send>) -> Self {
         Self {
-            detector: Detector::default(),
+            backend,
             probabilities: Vec::new(),
             leftovers: Vec::new(),
+            origin: 0,
+            padded: false,
+            enter_threshold: DEFAULT_THRESHOLD,
+            exit_threshold: DEFAULT_THRESHOLD,
+            min_gap_cs: 0,
+            min_speech_cs: 0,
+            min_silence_cs: 0,
+            resampler: None,
         }
     }
 
+    /// A VAD consuming audio at `rate_hz`, resampled to 16kHz internally.
+    /// All centisecond values, `end_cs()` included, stay based on the
+    /// resampled 16kHz stream, as do `end_samples()` and `consume_at`.
+    pub fn with_sample_rate(rate_hz: u32) -> Self {
+        assert!(rate_hz > 0, "sample rate must be positive");
+        let mut vad = Self::new();
+        if rate_hz != SAMPLE_RATE {
+            vad.resampler = Some(Resampler::new(rate_hz));
+        }
+        vad
+    }
+
+    /// Separate thresholds for entering and leaving speech, so that
+    /// probabilities hovering around a single threshold don't flap.
+    pub fn set_hysteresis(&mut self, enter: f32, exit: f32) {
+        assert!(exit <= enter, "exit threshold must not exceed enter");
+        self.enter_threshold = enter;
+        self.exit_threshold = exit;
+    }
+
+    /// Minimum time the probability has to stay on the other side of the
+    /// hysteresis thresholds before `speech_events` reports a transition.
+    pub fn set_dwell(&mut self, min_speech_cs: i64, min_silence_cs: i64) {
+        self.min_speech_cs = min_speech_cs.max(0);
+        self.min_silence_cs = min_silence_cs.max(0);
+    }
+
+    /// Merge `speech_spans` separated by less than `min_gap_cs`.
+    pub fn set_min_gap_cs(&mut self, min_gap_cs: i64) {
+        self.min_gap_cs = min_gap_cs.max(0);
+    }
+
     pub fn reset(&mut self) {
-        self.detector = Detector::default();
+        self.backend.reset();
         self.probabilities.clear();
         self.leftovers.clear();
+        self.origin = 0;
+        self.padded = false;
+        if let Some(ref mut resampler) = self.resampler {
+            resampler.reset();
+        }
+    }
+
+    pub fn export_state(&self) -> VadState {
+        VadState {
+            probabilities: self.probabilities.clone(),
+            leftovers: self.leftovers.clone(),
+            origin: self.origin,
+            padded: self.padded,
+        }
+    }
+
+    /// Replaces the history with `state`, resetting the backend and the
+    /// resampler. Thresholds and settings are kept.
+    pub fn import_state(&mut self, state: VadState) {
+        self.reset();
+        self.probabilities = state.probabilities;
+        self.leftovers = state.leftovers;
+        self.origin = state.origin;
+        self.padded = state.padded;
+    }
+
+    /// Forgets the audio before `cs`, which becomes the new cs 0. Frames
+    /// are kept as scored, a frame straddling `cs` stays and is offset
+    /// instead, so the remaining probabilities don't change.
+    pub fn drop_front(&mut self, cs: i64) {
+        let frame_len = self.backend.frame_len();
+        let drop = cs.max(0) as usize * CS_SAMPLES + self.origin;
+        let n_frames = (drop / frame_len).min(self.probabilities.len());
+        self.probabilities.drain(..n_frames);
+        let rest = drop - n_frames * frame_len;
+        if self.probabilities.is_empty() {
+            // the cut falls into the leftovers
+            self.leftovers.drain(..rest.min(self.leftovers.len()));
+            self.origin = 0;
+        } else {
+            self.origin = rest;
+        }
+    }
+
+    /// Samples consumed so far, including leftovers.
+    pub fn end_samples(&self) -> usize {
+        self.frame_end_samples() - self.origin
+    }
+
+    // the same, counted from the start of frame 0
+    fn frame_end_samples(&self) -> usize {
+        self.probabilities.len() * self.backend.frame_len()
+            + self.leftovers.len()
     }
 
+    // start of the `i`th frame, 0 for one straddling cs 0
+    fn frame_cs(&self, i: usize) -> i64 {
+        let start = (i * self.backend.frame_len()).saturating_sub(self.origin);
+        (start / CS_SAMPLES) as i64
+    }
+
+    // origin in cs, for the fractional frame positions
+    fn origin_cs(&self) -> f32 {
+        self.origin as f32 / CS_SAMPLES as f32
+    }
+
+    /// Consumes samples that start at `start_cs`. Gaps before it are filled
+    /// with `GAP_PROBABILITY`, samples overlapping already consumed audio
+    /// replace the probabilities of the frames they fully cover.
+    pub fn consume_at(&mut self, start_cs: i64, samples: &[i16]) {
+        let resampled = self.resample(samples);
+        let samples = resampled.as_deref().unwrap_or(samples);
+        let start = start_cs.max(0) as usize * CS_SAMPLES + self.origin;
+        let end = self.frame_end_samples();
+
+        if start > end {
+            self.skip(start - end);
+            self.consume_resampled(samples);
+            return;
+        }
+
+        // out of order: rescore frames fully covered by the late samples
+        let frame_len = self.backend.frame_len();
+        let split = (end - start).min(samples.len());
+        let (late, new) = samples.split_at(split);
+        let first_frame = start.div_ceil(frame_len);
+        let mut pos = first_frame * frame_len - start;
+        let mut frame = first_frame;
+        while pos + frame_len <= late.len() && frame < self.probabilities.len()
+        {
+            let chunk = &late[pos..(pos + frame_len)];
+            self.probabilities[frame] = self.backend.predict_frame(chunk);
+            pos += frame_len;
+            frame += 1;
+        }
+        self.consume_resampled(new);
+    }
+
+    // None if no resampling is needed
+    fn resample(&mut self, samples: &[i16]) -> Option<Vec<i16>> {
+        Some(self.resampler.as_mut()?.process(samples))
+    }
+
+    // advance by `n_samples` that were never delivered
+    fn skip(&mut self, mut n_samples: usize) {
+        let frame_len = self.backend.frame_len();
+        if !self.leftovers.is_empty() {
+            let need = frame_len - self.leftovers.len();
+            if n_samples < need {
+                self.leftovers.resize(self.leftovers.len() + n_samples, 0);
+                return;
+            }
+            self.leftovers.clear();
+            self.probabilities.push(GAP_PROBABILITY);
+            n_samples -= need;
+        }
+        let n_frames = n_samples / frame_len;
+        self.probabilities
+            .resize(self.probabilities.len() + n_frames, GAP_PROBABILITY);
+        self.leftovers.resize(n_samples % frame_len, 0);
+        self.padded = false;
+    }
+
+    /// Consumes samples directly following the previously consumed ones,
+    /// use `consume_at` if audio can arrive out of order.
+    /// Empty slices (e.g. from a lost frame without concealment) are no-ops.
+    ///
+    /// Panics after a `flush` that padded the last frame: where the audio
+    /// ended is no longer known, the samples have to be placed with
+    /// `consume_at` instead.
     pub fn consume(&mut self, samples: &[i16]) {
+        assert!(
+            !self.padded || samples.is_empty(),
+            "consume after flush would shift the audio, use consume_at"
+        );
+        match self.resample(samples) {
+            Some(resampled) => self.consume_resampled(&resampled),
+            None => self.consume_resampled(samples),
+        }
+    }
+
+    // consume samples already at 16kHz
+    fn consume_resampled(&mut self, samples: &[i16]) {
+        if samples.is_empty() {
+            return;
+        }
+        self.padded = false; // the padding is followed by audio now
+        let frame_len = self.backend.frame_len();
         let mut pos = 0;
 
         if !self.leftovers.is_empty() {
-            let need = EARSHOT_FRAME - self.leftovers.len();
+            let need = frame_len - self.leftovers.len();
             if samples.len() < need {
                 self.leftovers.extend_from_slice(samples);
                 return; // still not enough
             }
             self.leftovers.extend_from_slice(&samples[..need]);
             self.probabilities
-                .push(self.detector.predict_i16(&self.leftovers));
+                .push(self.backend.predict_frame(&self.leftovers));
             self.leftovers.clear();
             pos = need;
         }
 
-        while pos + EARSHOT_FRAME <= samples.len() {
-            let chunk = &samples[pos..(pos + EARSHOT_FRAME)];
-            self.probabilities.push(self.detector.predict_i16(chunk));
-            pos += EARSHOT_FRAME;
+        while pos + frame_len <= samples.len() {
+            let chunk = &samples[pos..(pos + frame_len)];
+            self.probabilities.push(self.backend.predict_frame(chunk));
+            pos += frame_len;
         }
 
         if pos < samples.len() {
@@ -57,26 +367,256 @@ impl Vad {
         }
     }
 
+    /// Scores the leftover samples zero-padded to a full frame, so that the
+    /// end of the stream gets a probability too. Meant for the end of the
+    /// audio, later samples have to be placed past the padding with
+    /// `consume_at`. Does nothing without leftovers.
+    pub fn flush(&mut self) {
+        if self.leftovers.is_empty() {
+            return;
+        }
+        self.leftovers.resize(self.backend.frame_len(), 0);
+        self.probabilities
+            .push(self.backend.predict_frame(&self.leftovers));
+        self.leftovers.clear();
+        self.padded = true;
+    }
+
+    /// Speech probability at `cs`, interpolated between frames.
+    /// 0.0 until at least one full frame has been consumed.
     pub fn probability_at_cs(&self, cs: i64) -> f32 {
-        let t_ms = cs as f32 * 10.0;
-        let probabilities_pos: f32 = t_ms / (EARSHOT_MS as f32);
+        let samples = cs as f32 * CS_SAMPLES as f32 + self.origin as f32;
+        let probabilities_pos: f32 = samples / self.backend.frame_len() as f32;
 
-        if self.probabilities.is_empty() {
+        let Some(last) = self.probabilities.len().checked_sub(1) else {
             return 0.0;
+        };
+
+        // clamping keeps the interpolation continuous into both ends
+        let pos = probabilities_pos.clamp(0.0, last as f32);
+        let lo = (pos.floor() as usize).min(last);
+        let hi = (lo + 1).min(last);
+        let hi_weight = pos - lo as f32;
+        let lo_val = self.probabilities[lo];
+        let hi_val = self.probabilities[hi];
+        hi_val * hi_weight + lo_val * (1. - hi_weight)
+    }
+
+    /// Mean frame probability over a window of `window_cs` centered on
+    /// `cs`, clamped to the consumed audio. Frames partially inside the
+    /// window count proportionally.
+    pub fn smoothed_probability_at_cs(&self, cs: i64, window_cs: i64) -> f32 {
+        let frame_cs = self.frame_duration_cs();
+        let total_cs = self.probabilities.len() as f32 * frame_cs;
+        let half = window_cs.max(0) as f32 / 2.;
+        // in cs from the start of frame 0, the audio begins at origin_cs
+        let begin_cs = self.origin_cs();
+        let center = cs as f32 + begin_cs;
+        let from = (center - half).max(begin_cs).min(total_cs);
+        let to = (center + half).max(begin_cs).min(total_cs);
+        if to <= from {
+            return self.probability_at_cs(cs);
         }
 
-        if probabilities_pos <= 0.0 {
-            return self.probabilities[0];
+        let first = (from / frame_cs).floor() as usize;
+        let last =
+            ((to / frame_cs).ceil() as usize).min(self.probabilities.len());
+        let mut sum = 0.;
+        for (i, &p) in self.probabilities[first..last].iter().enumerate() {
+            let start = (first + i) as f32 * frame_cs;
+            let overlap = (start + frame_cs).min(to) - start.max(from);
+            sum += p * overlap.max(0.);
         }
+        sum / (to - from)
+    }
 
-        let lo = probabilities_pos.floor() as usize;
-        if lo >= self.probabilities.len() - 1 {
-            return self.end_p();
+    // frames overlapping start_cs..end_cs, clamped to the buffer
+    fn frames_in(&self, start_cs: i64, end_cs: i64) -> &[f32] {
+        let frame_cs = self.frame_duration_cs();
+        let start = start_cs.max(0) as f32 + self.origin_cs();
+        let end = end_cs.max(0) as f32 + self.origin_cs();
+        let first = (start / frame_cs).floor() as usize;
+        let last = (end / frame_cs).ceil() as usize;
+        let last = last.min(self.probabilities.len());
+        if end_cs <= start_cs || first >= last {
+            return &[];
         }
-        let lo_val = self.probabilities[lo];
-        let hi_val = self.probabilities[lo + 1];
-        let hi_weight = probabilities_pos - lo as f32;
-        hi_val * hi_weight + lo_val * (1. - hi_weight)
+        &self.probabilities[first..last]
+    }
+
+    /// Peak frame probability between `start_cs` and `end_cs`,
+    /// 0.0 for an empty or inverted range.
+    pub fn max_probability_in(&self, start_cs: i64, end_cs: i64) -> f32 {
+        self.frames_in(start_cs, end_cs)
+            .iter()
+            .copied()
+            .fold(0.0, f32::max)
+    }
+
+    /// Mean frame probability between `start_cs` and `end_cs`,
+    /// 0.0 for an empty or inverted range.
+    pub fn mean_probability_in(&self, start_cs: i64, end_cs: i64) -> f32 {
+        let frames = self.frames_in(start_cs, end_cs);
+        if frames.is_empty() {
+            return 0.0;
+        }
+        frames.iter().sum::<f32>() / frames.len() as f32
+    }
+
+    /// Total duration of the speech regions.
+    pub fn speech_cs(&self) -> i64 {
+        self.speech_regions()
+            .iter()
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    /// Speech regions as (start_cs, end_cs) pairs, according to the
+    /// hysteresis thresholds. A region still open extends to `end_cs()`.
+    pub fn speech_regions(&self) -> Vec<(i64, i64)> {
+        let frame_cs = |i: usize| self.frame_cs(i);
+        let mut regions = Vec::new();
+        let mut start = None;
+        for (i, &p) in self.probabilities.iter().enumerate() {
+            match start {
+                None if p >= self.enter_threshold => start = Some(i),
+                Some(s) if p < self.exit_threshold => {
+                    regions.push((frame_cs(s), frame_cs(i)));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            regions.push((frame_cs(s), self.end_cs()));
+        }
+        regions
+    }
+
+    /// Contiguous runs of frames at or above `threshold` as (start_cs,
+    /// end_cs) pairs, ignoring the hysteresis. Gaps shorter than
+    /// `min_gap_cs` are merged, a span still open extends to `end_cs()`.
+    pub fn speech_spans(&self, threshold: f32) -> Vec<(i64, i64)> {
+        let frame_cs = |i: usize| self.frame_cs(i);
+        let mut spans: Vec<(i64, i64)> = Vec::new();
+        let mut push = |start: i64, end: i64| match spans.last_mut() {
+            Some(last) if start - last.1 < self.min_gap_cs => last.1 = end,
+            _ => spans.push((start, end)),
+        };
+        let mut start = None;
+        for (i, &p) in self.probabilities.iter().enumerate() {
+            match start {
+                None if p >= threshold => start = Some(i),
+                Some(s) if p < threshold => {
+                    push(frame_cs(s), frame_cs(i));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            push(frame_cs(s), self.end_cs());
+        }
+        spans
+    }
+
+    /// Runs of frames below `threshold` lasting at least `min_len_cs`, as
+    /// (start_cs, end_cs) pairs. Silence still going on at the end of the
+    /// buffer is reported up to `end_cs()`.
+    pub fn silence_gaps(
+        &self,
+        threshold: f32,
+        min_len_cs: i64,
+    ) -> Vec<(i64, i64)> {
+        let mut gaps = Vec::new();
+        let mut start = None;
+        for (i, &p) in self.probabilities.iter().enumerate() {
+            match start {
+                None if p < threshold => start = Some(i),
+                Some(s) if p >= threshold => {
+                    gaps.push((self.frame_cs(s), self.frame_cs(i)));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            gaps.push((self.frame_cs(s), self.end_cs()));
+        }
+        gaps.retain(|(start, end)| end - start >= min_len_cs);
+        gaps
+    }
+
+    /// Speech starts and ends according to the hysteresis thresholds and
+    /// dwell times. A start needs the probability to reach the enter
+    /// threshold and not fall below the exit one for `min_speech_cs`, an
+    /// end the reverse for `min_silence_cs`. Events are timestamped where
+    /// the transition began, speech still going on has no end event.
+    pub fn speech_events(&self) -> impl Iterator<Item = SpeechEvent> {
+        let mut events = Vec::new();
+        let mut speaking = false;
+        let mut candidate: Option<usize> = None; // first frame of transition
+        for (i, &p) in self.probabilities.iter().enumerate() {
+            let (enter, exit) =
+                (p >= self.enter_threshold, p < self.exit_threshold);
+            let (begins, cancels, dwell) = if speaking {
+                (exit, enter, self.min_silence_cs)
+            } else {
+                (enter, exit, self.min_speech_cs)
+            };
+            match candidate {
+                None if begins => candidate = Some(i),
+                Some(_) if cancels => candidate = None,
+                _ => {}
+            }
+            let Some(c) = candidate else { continue };
+            if self.frame_cs(i + 1) - self.frame_cs(c) >= dwell {
+                let cs = self.frame_cs(c);
+                events.push(if speaking {
+                    SpeechEvent::SpeechEnd { cs }
+                } else {
+                    SpeechEvent::SpeechStart { cs }
+                });
+                speaking = !speaking;
+                candidate = None;
+            }
+        }
+        events.into_iter()
+    }
+
+    /// Debounced speech state at `cs`, see `speech_events`.
+    pub fn speech_state_at_cs(&self, cs: i64) -> bool {
+        let mut speaking = false;
+        for event in self.speech_events() {
+            match event {
+                SpeechEvent::SpeechStart { cs: at } if at <= cs => {
+                    speaking = true
+                }
+                SpeechEvent::SpeechEnd { cs: at } if at <= cs => {
+                    speaking = false
+                }
+                _ => break,
+            }
+        }
+        speaking
+    }
+
+    /// Whether `cs` falls into one of the `speech_regions`.
+    pub fn is_speech_at_cs(&self, cs: i64) -> bool {
+        self.speech_regions()
+            .iter()
+            .any(|&(start, end)| start <= cs && cs < end)
+    }
+
+    /// Raw per-frame probabilities, see `frame_duration_cs`.
+    pub fn probabilities(&self) -> &[f32] {
+        &self.probabilities
+    }
+
+    /// Duration of one frame, 1.6cs with earshot. Not const, as it depends
+    /// on the backend.
+    pub fn frame_duration_cs(&self) -> f32 {
+        self.backend.frame_len() as f32 / CS_SAMPLES as f32
     }
 
     pub fn end_p(&self) -> f32 {
@@ -84,7 +624,398 @@ impl Vad {
     }
 
     pub fn end_cs(&self) -> i64 {
-        let t_ms = self.probabilities.len() * EARSHOT_MS;
-        (t_ms as f32 / 10.).floor() as i64
+        self.frame_cs(self.probabilities.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // scores 1cs frames by their first sample, in thousandths
+    struct Scripted;
+
+    impl VadBackend for Scripted {
+        fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+            samples[0] as f32 / 1000.
+        }
+
+        fn frame_len(&self) -> usize {
+            CS_SAMPLES
+        }
+    }
+
+    // a VAD that consumed one frame per probability
+    fn scripted(probabilities: &[f32]) -> Vad {
+        let mut vad = Vad::with_backend(Box::new(Scripted));
+        for &p in probabilities {
+            vad.consume(&[(p * 1000.) as i16; CS_SAMPLES]);
+        }
+        vad
+    }
+
+    // a second of noise bursts, alternately loud and quiet every 0.25s
+    fn bursts() -> Vec<i16> {
+        (0..16000)
+            .map(|i: i32| {
+                let noise = (i * 7919 % 2001 - 1000) as i16;
+                if i / 4000 % 2 == 0 {
+                    noise * 8
+                } else {
+                    noise / 8
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunked_consumption_matches_one_shot() {
+        let samples = bursts();
+        let mut whole = Vad::new();
+        whole.consume(&samples);
+
+        let mut chunked = Vad::new();
+        let mut rest = &samples[..];
+        for len in [37, 256, 513, 1000].into_iter().cycle() {
+            let (chunk, tail) = rest.split_at(len.min(rest.len()));
+            chunked.consume(chunk);
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+
+        assert_eq!(chunked.probabilities(), whole.probabilities());
+        assert_eq!(chunked.end_samples(), samples.len());
+        assert_eq!(chunked.end_samples(), whole.end_samples());
+    }
+
+    #[test]
+    fn speech_cs_sums_the_speech_regions() {
+        let vad = scripted(&[0.0, 0.9, 0.9, 0.2, 0.8, 0.0, 0.6]);
+        assert_eq!(vad.speech_regions(), [(1, 3), (4, 5), (6, 7)]);
+        assert_eq!(vad.speech_cs(), 4);
+        assert_eq!(scripted(&[]).speech_cs(), 0);
+    }
+
+    #[test]
+    fn hysteresis_keeps_hovering_speech_in_one_region() {
+        let hovering = [0.0, 0.7, 0.45, 0.55, 0.45, 0.2, 0.0, 0.55];
+        let mut vad = scripted(&hovering);
+        assert_eq!(vad.speech_regions(), [(1, 2), (3, 4), (7, 8)]);
+        vad.set_hysteresis(0.6, 0.3);
+        assert_eq!(vad.speech_regions(), [(1, 5)]);
+    }
+
+    #[test]
+    fn empty_slices_are_no_ops() {
+        let mut vad = scripted(&[0.9]);
+        vad.consume(&[500; CS_SAMPLES / 2]); // half a frame left over
+        vad.consume(&[]);
+        assert_eq!(vad.end_samples(), CS_SAMPLES * 3 / 2);
+        vad.consume(&[500; CS_SAMPLES / 2]);
+        assert_eq!(vad.probabilities(), [0.9, 0.5]);
+        assert_eq!(vad.end_samples(), 2 * CS_SAMPLES);
+    }
+
+    #[test]
+    fn raising_the_threshold_drops_weak_speech() {
+        let mut vad = Vad::with_threshold(0.8);
+        vad.backend = Box::new(Scripted);
+        for p in [0.0, 0.9, 0.6, 0.0, 0.85] {
+            vad.consume(&[(p * 1000.) as i16; CS_SAMPLES]);
+        }
+        assert_eq!(vad.speech_regions(), [(1, 2), (4, 5)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "within [0, 1]")]
+    fn thresholds_are_probabilities() {
+        Vad::with_threshold(1.5);
+    }
+
+    #[test]
+    fn speech_spans_merge_short_gaps() {
+        let mut vad = scripted(&[0.9, 0.9, 0.1, 0.9, 0.1, 0.1, 0.1, 0.7]);
+        assert_eq!(vad.speech_spans(0.5), [(0, 2), (3, 4), (7, 8)]);
+        assert_eq!(vad.speech_spans(0.8), [(0, 2), (3, 4)]);
+        vad.set_min_gap_cs(2);
+        assert_eq!(vad.speech_spans(0.5), [(0, 4), (7, 8)]);
+        vad.set_min_gap_cs(4);
+        assert_eq!(vad.speech_spans(0.5), [(0, 8)]);
+    }
+
+    // a detector unsure about everything, with 20ms frames
+    struct Unsure;
+
+    impl VadBackend for Unsure {
+        fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+            assert_eq!(samples.len(), 2 * CS_SAMPLES);
+            0.7
+        }
+
+        fn frame_len(&self) -> usize {
+            2 * CS_SAMPLES
+        }
+    }
+
+    #[test]
+    fn any_backend_scores_whole_frames() {
+        let mut vad = Vad::with_backend(Box::new(Unsure));
+        vad.consume(&[0; 1000]);
+        assert_eq!(vad.probabilities(), [0.7; 3]);
+        assert_eq!(vad.end_samples(), 1000);
+        assert_eq!(vad.probability_at_cs(5), 0.7);
+        assert_eq!(vad.speech_regions(), [(0, 6)]);
+    }
+
+    #[cfg(feature = "vad-energy")]
+    #[test]
+    fn energy_detector_scores_loudness_above_the_floor() {
+        let mut vad = Vad::with_backend(Box::new(EnergyDetector::default()));
+        // the floor settles during a quiet second before the loud burst
+        let quiet: Vec<i16> = bursts()[4000..8000].to_vec();
+        vad.consume(&quiet.repeat(4));
+        vad.consume(&bursts()[..4000]);
+        let probabilities = vad.probabilities();
+        let quiet = probabilities[probabilities.len() - 20];
+        let loud = probabilities[probabilities.len() - 2];
+        assert!(loud > 0.9, "loud burst scored {}", loud);
+        assert!(quiet < 0.1, "quiet burst scored {}", quiet);
+    }
+
+    #[test]
+    fn probability_at_cs_is_continuous_at_the_ends() {
+        let empty = scripted(&[]);
+        assert_eq!(empty.frame_duration_cs(), 1.);
+        assert_eq!(empty.probability_at_cs(0), 0.);
+
+        let one = scripted(&[0.4]);
+        assert_eq!(one.probability_at_cs(-5), 0.4);
+        assert_eq!(one.probability_at_cs(0), 0.4);
+        assert_eq!(one.probability_at_cs(3), 0.4);
+
+        let two = scripted(&[0.2, 0.8]);
+        assert_eq!(two.probability_at_cs(0), 0.2);
+        assert_eq!(two.probability_at_cs(1), 0.8);
+        assert_eq!(two.probability_at_cs(2), 0.8);
+        let coarse = Vad::with_backend(Box::new(Unsure));
+        assert_eq!(coarse.frame_duration_cs(), 2.);
+    }
+
+    #[test]
+    fn flush_scores_the_tail() {
+        let mut vad = scripted(&[0.1]);
+        vad.consume(&[800; CS_SAMPLES / 4]);
+        assert_eq!(vad.probabilities(), [0.1]);
+        vad.flush();
+        assert_eq!(vad.probabilities(), [0.1, 0.8]);
+        vad.flush(); // nothing left over
+        assert_eq!(vad.probabilities().len(), 2);
+    }
+
+    #[test]
+    fn exported_state_survives_a_roundtrip() {
+        let mut vad = scripted(&[0.2, 0.9]);
+        vad.consume(&[700; CS_SAMPLES / 2]);
+        vad.drop_front(1);
+        let json = serde_json::to_string(&vad.export_state()).unwrap();
+
+        let mut restored = scripted(&[0.5, 0.5, 0.5]);
+        restored.import_state(serde_json::from_str(&json).unwrap());
+        for v in [&mut vad, &mut restored] {
+            v.consume(&[700; CS_SAMPLES / 2]);
+        }
+        assert_eq!(restored.probabilities(), vad.probabilities());
+        assert_eq!(restored.end_samples(), vad.end_samples());
+        assert_eq!(restored.probability_at_cs(1), 0.7);
+    }
+
+    #[test]
+    fn drop_front_keeps_the_remaining_scores() {
+        let mut vad = Vad::with_backend(Box::new(Unsure)); // 2cs frames
+        vad.consume(&[0; 1000]);
+        vad.drop_front(1); // into the first frame, which stays
+        assert_eq!(vad.probabilities(), [0.7; 3]);
+        assert_eq!(vad.end_samples(), 1000 - CS_SAMPLES);
+        assert_eq!(vad.speech_regions(), [(0, 5)]);
+        vad.drop_front(1); // the rest of it
+        assert_eq!(vad.probabilities(), [0.7; 2]);
+        assert_eq!(vad.end_samples(), 1000 - 2 * CS_SAMPLES);
+        vad.drop_front(10); // past the leftovers
+        assert!(vad.probabilities().is_empty());
+        assert_eq!(vad.end_samples(), 0);
+    }
+
+    #[test]
+    fn out_of_order_chunks_land_where_they_belong() {
+        let mut vad = scripted(&[]);
+        vad.consume_at(3, &[900; 2 * CS_SAMPLES]); // 0..3 never arrived yet
+        assert_eq!(
+            vad.probabilities(),
+            [GAP_PROBABILITY, GAP_PROBABILITY, GAP_PROBABILITY, 0.9, 0.9]
+        );
+        vad.consume_at(1, &[500; CS_SAMPLES]);
+        vad.consume_at(5, &[200; CS_SAMPLES]);
+        vad.consume_at(0, &[100; CS_SAMPLES]);
+        assert_eq!(vad.probabilities(), [0.1, 0.5, 0., 0.9, 0.9, 0.2]);
+        // late, but reaching past the end
+        vad.consume_at(5, &[300; 2 * CS_SAMPLES]);
+        assert_eq!(vad.probabilities(), [0.1, 0.5, 0., 0.9, 0.9, 0.3, 0.3]);
+        assert_eq!(vad.end_samples(), 7 * CS_SAMPLES);
+    }
+
+    #[test]
+    fn audio_after_a_flush_is_placed_past_the_padding() {
+        let mut vad = scripted(&[0.1]);
+        vad.consume(&[800; CS_SAMPLES / 2]);
+        vad.flush();
+        vad.consume_at(2, &[400; CS_SAMPLES]);
+        assert_eq!(vad.probabilities(), [0.1, 0.8, 0.4]);
+        vad.consume(&[600; CS_SAMPLES]); // in order again
+        assert_eq!(vad.probabilities(), [0.1, 0.8, 0.4, 0.6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "consume after flush")]
+    fn consume_after_a_flush_is_refused() {
+        let mut vad = scripted(&[0.1]);
+        vad.consume(&[800; CS_SAMPLES / 2]);
+        vad.flush();
+        vad.consume(&[400; CS_SAMPLES]);
+    }
+
+    #[test]
+    fn smoothing_weighs_partial_frames() {
+        let vad = scripted(&[0.2, 0.4, 0.8, 1.0]);
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+        // 0.5cs..3.5cs: half of the first and last frames
+        let p = vad.smoothed_probability_at_cs(2, 3);
+        assert!(close(p, (0.1 + 0.4 + 0.8 + 0.5) / 3.), "got {}", p);
+        // 0.5cs..5.5cs, clamped to the 4cs consumed
+        let p = vad.smoothed_probability_at_cs(3, 5);
+        assert!(close(p, (0.1 + 0.4 + 0.8 + 1.0) / 3.5), "got {}", p);
+        // -0.5cs..2.5cs, clamped to the start
+        let p = vad.smoothed_probability_at_cs(1, 3);
+        assert!(close(p, (0.2 + 0.4 + 0.4) / 2.5), "got {}", p);
+        // nothing to average over
+        assert_eq!(vad.smoothed_probability_at_cs(1, 0), 0.4);
+        assert_eq!(vad.smoothed_probability_at_cs(9, 4), 1.0);
+        assert_eq!(scripted(&[]).smoothed_probability_at_cs(0, 10), 0.);
+    }
+
+    #[test]
+    fn resampled_audio_keeps_16khz_timing() {
+        let mut vad = Vad::with_sample_rate(48000);
+        vad.backend = Box::new(Scripted);
+        // a second at 48kHz, silent for its first half
+        let mut input = vec![0; 24000];
+        input.extend([900; 24000]);
+        let mut rest = &input[..];
+        for len in [1001, 4799, 37, 480].into_iter().cycle() {
+            let (chunk, tail) = rest.split_at(len.min(rest.len()));
+            vad.consume(chunk);
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(vad.end_samples(), 16000);
+        assert_eq!(vad.end_cs(), 100);
+        assert_eq!(vad.probability_at_cs(49), 0.);
+        assert_eq!(vad.probability_at_cs(50), 0.9);
+        assert_eq!(vad.speech_regions(), [(50, 100)]);
+    }
+
+    #[test]
+    fn probabilities_in_ranges_clamp_to_the_buffer() {
+        let vad = scripted(&[0.2, 0.8, 0.4, 0.6]);
+        assert_eq!(vad.max_probability_in(1, 3), 0.8);
+        assert_eq!(vad.mean_probability_in(1, 3), 0.6);
+        // empty, inverted or past the end
+        for (start, end) in [(2, 2), (3, 1), (10, 20)] {
+            assert_eq!(vad.max_probability_in(start, end), 0.);
+            assert_eq!(vad.mean_probability_in(start, end), 0.);
+        }
+        // reaching out of either end
+        assert_eq!(vad.max_probability_in(-5, 2), 0.8);
+        assert_eq!(vad.mean_probability_in(-5, 2), 0.5);
+        assert_eq!(vad.max_probability_in(2, 99), 0.6);
+        assert_eq!(vad.mean_probability_in(2, 99), 0.5);
+    }
+
+    #[test]
+    fn silence_gaps_run_to_the_end() {
+        let vad = scripted(&[0.9, 0.1, 0.1, 0.9, 0.1, 0.9, 0.1, 0.1, 0.1]);
+        assert_eq!(vad.silence_gaps(0.5, 0), [(1, 3), (4, 5), (6, 9)]);
+        assert_eq!(vad.silence_gaps(0.5, 2), [(1, 3), (6, 9)]);
+        assert_eq!(vad.silence_gaps(0.5, 4), []);
+        assert_eq!(vad.end_cs(), 9);
+    }
+
+    #[test]
+    fn trimming_keeps_a_pre_roll_before_the_onset() {
+        let silence = [0.1; 30];
+        let vad = scripted(&[&silence[..], &[0.9; 10]].concat());
+        let samples = vec![0; vad.end_samples()];
+        let onset = 30 * CS_SAMPLES;
+        assert_eq!(
+            trim_leading_silence(&samples, &vad, 0.5),
+            onset - PRE_ROLL_SAMPLES
+        );
+        // no speech, or too early for a full pre-roll
+        assert_eq!(trim_leading_silence(&samples, &scripted(&silence), 0.5), 0);
+        assert_eq!(
+            trim_leading_silence(&samples, &scripted(&[0.1, 0.9]), 0.5),
+            0
+        );
+
+        // after a cut into a 2cs frame, counted from the cut
+        struct Coarse;
+        impl VadBackend for Coarse {
+            fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+                samples[0] as f32 / 1000.
+            }
+
+            fn frame_len(&self) -> usize {
+                2 * CS_SAMPLES
+            }
+        }
+        let mut vad = Vad::with_backend(Box::new(Coarse));
+        vad.consume(&[0; 40 * CS_SAMPLES]);
+        vad.consume(&[900; 8 * CS_SAMPLES]);
+        vad.drop_front(5);
+        let samples = vec![0; vad.end_samples()];
+        assert_eq!(
+            trim_leading_silence(&samples, &vad, 0.5),
+            35 * CS_SAMPLES - PRE_ROLL_SAMPLES
+        );
+    }
+
+    #[test]
+    fn speech_events_are_debounced() {
+        let mut vad = scripted(&[
+            0.1, 0.9, 0.9, 0.1, 0.1, // a 2cs blip
+            0.9, 0.9, 0.9, 0.9, 0.9, // speech
+            0.5, 0.2, 0.9, 0.5, // hovering, dipping for 1cs
+            0.2, 0.2, 0.2, 0.2, // silence
+        ]);
+        let undebounced: Vec<_> = vad.speech_events().collect();
+        assert_eq!(undebounced[0], SpeechEvent::SpeechStart { cs: 1 });
+
+        vad.set_hysteresis(0.6, 0.3);
+        vad.set_dwell(3, 3);
+        let events: Vec<_> = vad.speech_events().collect();
+        assert_eq!(
+            events,
+            [
+                SpeechEvent::SpeechStart { cs: 5 },
+                SpeechEvent::SpeechEnd { cs: 14 }
+            ]
+        );
+        assert!(!vad.speech_state_at_cs(2));
+        assert!(vad.speech_state_at_cs(6));
+        assert!(vad.speech_state_at_cs(12));
+        assert!(!vad.speech_state_at_cs(15));
     }
 }
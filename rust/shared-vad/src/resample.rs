@@ -0,0 +1,64 @@
+/// Streaming linear resampler to the VAD's 16kHz. Crude, but speech
+/// detection doesn't need more.
+pub struct Resampler {
+    step: f64,         // input samples per output sample
+    pos: f64,          // next output position, relative to `prev`
+    prev: Option<i16>, // last input sample of the previous chunk
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32) -> Self {
+        Self {
+            step: input_rate as f64 / crate::SAMPLE_RATE as f64,
+            pos: 0.,
+            prev: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pos = 0.;
+        self.prev = None;
+    }
+
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        if samples.is_empty() {
+            return Vec::new();
+        }
+        // index 0 is the last sample of the previous chunk, if any
+        let input: Vec<i16> =
+            self.prev.iter().chain(samples).copied().collect();
+        let last = (input.len() - 1) as f64;
+        let mut output = Vec::with_capacity((last / self.step) as usize + 1);
+        while self.pos <= last {
+            let lo = self.pos.floor() as usize;
+            let hi = (lo + 1).min(input.len() - 1);
+            let frac = self.pos - lo as f64;
+            let s = input[lo] as f64 * (1. - frac) + input[hi] as f64 * frac;
+            output.push(s.round() as i16);
+            self.pos += self.step;
+        }
+        self.pos -= last;
+        self.prev = input.last().copied();
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_resample_like_the_whole() {
+        let input: Vec<i16> = (0..4800).map(|i| (i % 97) as i16).collect();
+        let whole = Resampler::new(48000).process(&input);
+        assert_eq!(whole.len(), 1600);
+        assert_eq!(whole[..4], [0, 3, 6, 9]); // every third sample
+
+        let mut resampler = Resampler::new(48000);
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(7).chain([&[][..]]) {
+            chunked.extend(resampler.process(chunk));
+        }
+        assert_eq!(chunked, whole);
+    }
+}
@@ -0,0 +1,114 @@
+/// Loudest and quietest gain auto-gain will apply.
+const MAX_GAIN_DB: f32 = 30.;
+const MIN_GAIN_DB: f32 = -20.;
+/// Level estimate time constant relative to the gain's, the level follows
+/// the speech faster than the gain follows the level.
+const LEVEL_SPEEDUP: f32 = 4.;
+/// Below this level a chunk is digital silence and carries no information.
+const SILENCE_DBFS: f32 = -90.;
+
+/// Normalizes speech toward a target level. The level estimate and the
+/// gain only move during speech, so pauses keep the last gain instead of
+/// ramping up the noise floor. The gained samples feed both the VAD and
+/// whisper, the VAD decisions gating the gain are the preceding audio's.
+pub struct AutoGain {
+    target_dbfs: f32,
+    smoothing: f32, // fraction of the way the gain moves per chunk, 0..1
+    level_dbfs: Option<f32>, // running speech level, None until first speech
+    gain_db: f32,
+}
+
+impl AutoGain {
+    pub fn new(target_dbfs: f32, smoothing: f32) -> Self {
+        Self {
+            target_dbfs,
+            smoothing: smoothing.clamp(0.001, 1.),
+            level_dbfs: None,
+            gain_db: 0.,
+        }
+    }
+
+    pub fn target_dbfs(&self) -> f32 {
+        self.target_dbfs
+    }
+
+    /// Applies the current gain to `samples` in place, first updating it
+    /// from their level if the VAD says they are `speech`.
+    pub fn process(&mut self, samples: &mut [i16], speech: bool) {
+        if speech {
+            let level = rms_dbfs(samples);
+            if level > SILENCE_DBFS {
+                let rate = (self.smoothing * LEVEL_SPEEDUP).min(1.);
+                let estimate = match self.level_dbfs {
+                    Some(prev) => prev + (level - prev) * rate,
+                    None => level,
+                };
+                self.level_dbfs = Some(estimate);
+                let wanted = (self.target_dbfs - estimate)
+                    .clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+                self.gain_db += (wanted - self.gain_db) * self.smoothing;
+            }
+        }
+        if self.gain_db == 0. {
+            return;
+        }
+        let factor = 10f32.powf(self.gain_db / 20.);
+        for s in samples.iter_mut() {
+            *s = (*s as f32 * factor).clamp(i16::MIN as f32, i16::MAX as f32)
+                as i16;
+        }
+    }
+}
+
+fn rms_dbfs(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let sum: f64 = samples.iter().map(|&s| (s as f64).powi(2)).sum();
+    let rms = (sum / samples.len() as f64).sqrt() / i16::MAX as f64;
+    20. * rms.log10() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: usize = 960;
+
+    fn chunk(amplitude: i16) -> Vec<i16> {
+        (0..CHUNK)
+            .map(|i| if i % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    #[test]
+    fn alternating_speech_settles_on_the_mean_level() {
+        let (loud, quiet) = (chunk(16000), chunk(200));
+        let mean_dbfs = (rms_dbfs(&loud) + rms_dbfs(&quiet)) / 2.;
+        let mut gain = AutoGain::new(-20., 0.05);
+        let mut gains = Vec::new();
+        for _ in 0..200 {
+            gain.process(&mut loud.clone(), true);
+            gains.push(gain.gain_db);
+            gain.process(&mut quiet.clone(), true);
+            gains.push(gain.gain_db);
+        }
+        // neither pumps with every chunk nor chases the loud one
+        let last = &gains[gains.len() - 2..];
+        assert!((last[0] - last[1]).abs() < 1.);
+        assert!((last[1] - (-20. - mean_dbfs)).abs() < 3.);
+    }
+
+    #[test]
+    fn pauses_keep_the_gain() {
+        let mut gain = AutoGain::new(-20., 0.05);
+        for _ in 0..100 {
+            gain.process(&mut chunk(300), true);
+        }
+        let gain_db = gain.gain_db;
+        let mut noise = chunk(30);
+        gain.process(&mut noise, false);
+        assert_eq!(gain.gain_db, gain_db);
+        assert!(rms_dbfs(&noise) > rms_dbfs(&chunk(30)));
+    }
+}
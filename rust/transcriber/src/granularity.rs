@@ -0,0 +1,138 @@
+use shared_protocol::{Segment, majority_speaker, timestamp_unreliable};
+
+const PHRASE_MAX_LEN: i32 = 40; // chars, about a caption line
+const MIN_PHRASE_CS: i64 = 100; // shorter phrases are merged into the next
+
+/// High-level segment size, mapped to whisper parameters and merging.
+/// Explicit max_len or single_segment in Configure take precedence.
+///
+/// | granularity | max_len | split_on_word | single_segment | merging      |
+/// |-------------|---------|---------------|----------------|--------------|
+/// | word        | 1       | yes           | no             | none         |
+/// | phrase      | 40      | yes           | no             | under 1s     |
+/// | sentence    | 0       | no            | no             | until .?!    |
+/// | paragraph   | 0       | no            | yes            | none         |
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    Word,
+    Phrase,
+    Sentence,
+    Paragraph,
+}
+
+impl Granularity {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "word" => Some(Self::Word),
+            "phrase" => Some(Self::Phrase),
+            "sentence" => Some(Self::Sentence),
+            "paragraph" => Some(Self::Paragraph),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Word => "word",
+            Self::Phrase => "phrase",
+            Self::Sentence => "sentence",
+            Self::Paragraph => "paragraph",
+        }
+    }
+
+    pub fn max_len(self) -> i32 {
+        match self {
+            Self::Word => 1,
+            Self::Phrase => PHRASE_MAX_LEN,
+            Self::Sentence | Self::Paragraph => 0,
+        }
+    }
+
+    pub fn split_on_word(self) -> bool {
+        matches!(self, Self::Word | Self::Phrase)
+    }
+
+    pub fn single_segment(self) -> bool {
+        self == Self::Paragraph
+    }
+
+    /// Merges complete segments that are too small for the granularity
+    /// into the segment that follows them.
+    pub fn merge(self, segments: Vec<Segment>) -> Vec<Segment> {
+        let too_small = |s: &Segment| match self {
+            Self::Phrase => s.end_cs - s.start_cs < MIN_PHRASE_CS,
+            Self::Sentence => !s.text.ends_with(['.', '?', '!']),
+            Self::Word | Self::Paragraph => false,
+        };
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            match merged.last_mut() {
+                Some(prev) if too_small(prev) => append(prev, segment),
+                _ => merged.push(segment),
+            }
+        }
+        merged
+    }
+}
+
+fn append(to: &mut Segment, next: Segment) {
+    // weighted by the number of text tokens averaged over
+    let n_text = |s: &Segment| s.tokens.iter().filter(|t| !t.special).count();
+    let (n_to, n_next) = (n_text(to), n_text(&next));
+    if n_to + n_next > 0 {
+        to.avg_logprob = (to.avg_logprob * n_to as f32
+            + next.avg_logprob * n_next as f32)
+            / (n_to + n_next) as f32;
+    }
+    to.text = format!("{} {}", to.text, next.text).trim().to_string();
+    to.end_cs = next.end_cs;
+    to.end_tc = next.end_tc;
+    to.tokens.extend(next.tokens);
+    to.words.extend(next.words);
+    to.fallback_segmentation |= next.fallback_segmentation;
+    to.end_vad_probability = next.end_vad_probability;
+    to.no_speech_probability =
+        to.no_speech_probability.min(next.no_speech_probability);
+    to.is_final = next.is_final;
+    to.vad_whisper_disagreement = next.vad_whisper_disagreement;
+    to.hallucinated |= next.hallucinated;
+    to.timestamp_unreliable = timestamp_unreliable(to.start_cs, to.end_cs);
+    to.speaker_id = majority_speaker(&to.tokens);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{segment, token};
+
+    fn texts(segments: &[Segment]) -> Vec<&str> {
+        segments.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn sentences_are_merged_until_their_punctuation() {
+        let segments = vec![
+            segment(vec![token("So", 0, 50)]),
+            segment(vec![token("then.", 50, 120)]),
+            segment(vec![token("Why?", 120, 200)]),
+            segment(vec![token("Because", 200, 300)]),
+        ];
+        let merged = Granularity::Sentence.merge(segments);
+        assert_eq!(texts(&merged), ["So then.", "Why?", "Because"]);
+        assert_eq!((merged[0].start_cs, merged[0].end_cs), (0, 120));
+        assert_eq!(merged[0].tokens.len(), 2);
+    }
+
+    #[test]
+    fn short_phrases_are_merged_into_the_next() {
+        let segments = vec![
+            segment(vec![token("Well", 0, 40)]),
+            segment(vec![token("I", 40, 60)]),
+            segment(vec![token("think so", 60, 300)]),
+            segment(vec![token("yes", 300, 350)]),
+        ];
+        let merged = Granularity::Phrase.merge(segments.clone());
+        assert_eq!(texts(&merged), ["Well I think so", "yes"]);
+        assert_eq!(Granularity::Word.merge(segments).len(), 4);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::compare_segments;
+use shared_protocol::Segment;
+
+/// Length of a single long-form window, whisper's native 30s.
+pub const WINDOW_CS: i64 = 3000;
+/// Audio shared by consecutive windows, transcribed twice for stitching.
+pub const OVERLAP_CS: i64 = 500;
+
+/// Appends the segments of a window starting at `window_start_cs` to the
+/// already stitched `segments`. If a segment in the overlap is recognized by
+/// both windows, the seam is placed right after it; otherwise the overlap is
+/// split in the middle.
+pub fn stitch(
+    segments: &mut Vec<Segment>,
+    window: Vec<Segment>,
+    window_start_cs: i64,
+) {
+    let prev_end_cs = match segments.last() {
+        Some(s) => s.end_cs,
+        None => {
+            segments.extend(window);
+            return;
+        }
+    };
+
+    // look for a segment transcribed identically in both windows
+    let first_overlapping = segments
+        .iter()
+        .position(|s| s.end_cs > window_start_cs)
+        .unwrap_or(segments.len());
+    let anchor = window
+        .iter()
+        .take_while(|new| new.start_cs < prev_end_cs)
+        .enumerate()
+        .find_map(|(i, new)| {
+            let j = segments[first_overlapping..].iter().position(|old| {
+                compare_segments(old, std::slice::from_ref(new)).0
+            })?;
+            Some((i, first_overlapping + j))
+        });
+    if let Some((i, j)) = anchor {
+        segments.truncate(j + 1);
+        segments.extend(window.into_iter().skip(i + 1));
+        return;
+    }
+
+    // no anchor, cut in the middle of the overlap
+    let cut_cs = (window_start_cs + prev_end_cs) / 2;
+    segments.retain(|s| s.start_cs < cut_cs);
+    segments.extend(window.into_iter().filter(|s| s.start_cs >= cut_cs));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{segment, token};
+
+    fn seg(text: &str, start_cs: i64, end_cs: i64) -> Segment {
+        segment(vec![token(text, start_cs, end_cs)])
+    }
+
+    fn texts(segments: &[Segment]) -> Vec<&str> {
+        segments.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn first_window_is_taken_whole() {
+        let mut segments = Vec::new();
+        stitch(&mut segments, vec![seg(" one", 0, 1000)], 0);
+        assert_eq!(texts(&segments), [" one"]);
+    }
+
+    #[test]
+    fn seam_follows_a_segment_recognized_by_both_windows() {
+        let mut segments = vec![seg(" one", 0, 1000), seg(" two", 2500, 2900)];
+        let window = vec![seg(" Two.", 2510, 2890), seg(" three", 3000, 4000)];
+        stitch(&mut segments, window, 2500);
+        assert_eq!(texts(&segments), [" one", " two", " three"]);
+    }
+
+    #[test]
+    fn overlap_without_anchor_is_cut_in_the_middle() {
+        let mut segments = vec![seg(" one", 0, 1000), seg(" two", 2600, 2900)];
+        let window = vec![seg(" too", 2550, 2800), seg(" three", 2750, 3500)];
+        stitch(&mut segments, window, 2500); // cut at 2700
+        assert_eq!(texts(&segments), [" one", " two", " three"]);
+    }
+}
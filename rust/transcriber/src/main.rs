@@ -1,15 +1,41 @@
+mod gain;
+mod granularity;
+mod longform;
+mod metrics;
+mod preview;
+mod rate_limit;
 mod session;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod warm;
+mod wav;
 
 use anyhow::Result;
 use clap::Parser;
-use futures_util::{FutureExt, SinkExt, StreamExt};
+use futures_util::{FutureExt, SinkExt, Stream, StreamExt};
+use metrics::METRICS;
+use preview::PreviewModel;
+use rate_limit::RateLimiter;
 use session::{Session, TranscribeOpts};
-use shared_protocol::{ClientMessage, ServerMessage};
+use shared_protocol::{
+    ClientMessage, Config, PROTOCOL_VERSION, ServerMessage, ZSTD_FRAME_TAG,
+};
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, SemaphorePermit, broadcast, watch};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use warm::WarmPool;
 use whisper_rs::{SamplingStrategy, WhisperContext, WhisperContextParameters};
 
 #[derive(Parser, Debug)]
@@ -22,7 +48,10 @@ struct Args {
     port: u16,
 
     #[arg(short, long, help = "path to whisper model file")]
-    model: String, // path to whisper model file
+    model: String, // path to whisper model file, the default one
+
+    #[arg(long, help = "directory of more *.bin models clients can choose")]
+    model_dir: Option<PathBuf>,
 
     #[arg(long, help = "path to optional API token")]
     token_file: Option<String>,
@@ -55,11 +84,270 @@ struct Args {
     #[arg(long, help = "Entropy threshold for decode retry (default: 2.4)")]
     entropy_thold: Option<f32>,
 
+    #[arg(
+        long,
+        help = "Mean logprob threshold for decode retry (default: -1.0)"
+    )]
+    logprob_thold: Option<f32>,
+
+    #[arg(
+        long,
+        default_value = "1.0",
+        help = "Drop segments with a higher no_speech_probability (1.0 = none)"
+    )]
+    no_speech_thold: f32,
+
+    #[arg(
+        long,
+        default_value = "0.0",
+        help = "Trim trailing preview tokens less likely than this (0.0 = none)"
+    )]
+    min_token_prob: f32,
+
     #[arg(
         long,
         help = "Reinitialize whisper state before every transcription"
     )]
     reinit_state: bool,
+
+    #[arg(
+        long,
+        help = "Auto-gain speech toward -20 dBFS in sessions not configuring it"
+    )]
+    agc: bool,
+
+    #[arg(
+        long,
+        help = "Reject clients sending audio faster than this x realtime"
+    )]
+    max_audio_rate: Option<f64>,
+
+    #[arg(
+        long,
+        default_value = "18",
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "Shortest audio (or speech with min_speech) transcribed, in cs"
+    )]
+    min_audio_cs: i64,
+
+    #[arg(
+        long,
+        default_value = "2800",
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "Buffer length in cs beyond which sessions advance by themselves"
+    )]
+    max_window_cs: i64,
+
+    #[arg(long, help = "directory to write per-session WAV audio dumps to")]
+    dump_audio_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "3",
+        help = "Token n-gram repeats marking a segment hallucinated (0 = off)"
+    )]
+    hallucination_repeats: usize,
+
+    #[arg(
+        long,
+        default_value = "0.6",
+        help = "no_speech_probability marking a segment in VAD silence hallucinated"
+    )]
+    hallucination_no_speech: f32,
+
+    #[arg(
+        long,
+        help = "Max MiB of audio buffers transcribed concurrently (unlimited)"
+    )]
+    memory_budget_mb: Option<u32>,
+
+    #[arg(long, help = "Reject Configure messages with unknown fields")]
+    strict_config: bool,
+
+    #[arg(long, help = "directory to write per-session JSONL transcripts to")]
+    transcript_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Let clients request decoded audio echo (256 kbit/s each)"
+    )]
+    allow_audio_echo: bool,
+
+    #[arg(long, help = "Max new connections per minute per IP (unlimited)")]
+    rate_limit: Option<u32>,
+
+    #[arg(
+        long,
+        default_value = "5",
+        help = "Connections per IP allowed in a burst with --rate-limit"
+    )]
+    rate_limit_burst: u32,
+
+    #[arg(long, help = "Close connections not sending Configure in time")]
+    configure_timeout_secs: Option<u64>,
+
+    #[arg(long, help = "Close sessions receiving nothing for this long")]
+    session_idle_timeout_secs: Option<u64>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Whisper states kept ready for new sessions"
+    )]
+    warm_states: usize,
+
+    #[arg(
+        long,
+        default_value = "1000",
+        help = "Milliseconds of silence decoded to prime a warm state (0 = none)"
+    )]
+    warm_up_ms: u32,
+
+    #[arg(
+        long,
+        help = "Abort preview transcriptions made stale by newer client input"
+    )]
+    preemptible: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max concurrent connections, more are rejected (unlimited)"
+    )]
+    max_connections: Option<u32>,
+
+    #[arg(
+        long,
+        help = "small model decoding fast_preview alongside the full model"
+    )]
+    fast_preview_model: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "1",
+        value_parser = clap::value_parser!(i32).range(1..),
+        help = "Best-of of the greedy fast_preview decode"
+    )]
+    preview_best_of: i32,
+
+    #[arg(long, help = "port to serve Prometheus /metrics on (disabled)")]
+    metrics_port: Option<u16>,
+
+    #[arg(long, requires = "tls_key", help = "PEM certificate chain, for wss")]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(long, requires = "tls_cert", help = "PEM private key, for wss")]
+    tls_key: Option<PathBuf>,
+}
+
+const OUTPUTS_CAPACITY: usize = 64; // messages buffered per extra consumer
+const ZSTD_LEVEL: i32 = 3; // zstd's default, cheap enough per message
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10); // to finalize in
+const PREEMPT_FRAMES: usize = 16; // ~1s of Opus, see supersedes
+// between full transcriptions when a separate model makes the previews
+const FULL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server-wide settings shared by all connections.
+struct ServerOpts {
+    expected_token: Option<String>,
+    sampling_strategy: SamplingStrategy,
+    transcribe_opts: TranscribeOpts,
+    transcript_dir: Option<PathBuf>,
+    allow_audio_echo: bool,
+    memory_budget: Option<MemoryBudget>,
+    strict_config: bool,
+    configure_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>, // see idle_expired
+    warm_pool: Option<Arc<WarmPool>>,
+    preemptible: bool,
+    shutdown: watch::Receiver<bool>,
+    models: HashMap<String, Arc<Model>>, // by name, the default one included
+    fast_preview_model: Option<Model>,
+}
+
+/// Bounds the total size of the buffers transcribed at the same time,
+/// in KiB.
+struct MemoryBudget {
+    semaphore: Semaphore,
+    kib: u32,
+}
+
+impl MemoryBudget {
+    fn new(mb: u32) -> Self {
+        let kib = mb.saturating_mul(1024).max(1);
+        Self {
+            semaphore: Semaphore::new(kib as usize),
+            kib,
+        }
+    }
+
+    /// Waits until `bytes` fit into the budget. A single reservation larger
+    /// than the whole budget just waits for exclusive use.
+    async fn reserve(&self, bytes: usize) -> Result<SemaphorePermit<'_>> {
+        let kib = (bytes / 1024).clamp(1, self.kib as usize) as u32;
+        match self.semaphore.try_acquire_many(kib) {
+            Ok(permit) => Ok(permit),
+            Err(_) => {
+                info!("waiting for memory budget for {} KiB", kib);
+                Ok(self.semaphore.acquire_many(kib).await?)
+            }
+        }
+    }
+}
+
+/// A whisper model that can be reloaded at runtime. Sessions keep the
+/// context they were created with until they end.
+struct Model {
+    path: String,
+    ctx: RwLock<Arc<WhisperContext>>,
+}
+
+impl Model {
+    fn load(path: &str) -> Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            ctx: RwLock::new(Arc::new(load_context(path)?)),
+        })
+    }
+
+    /// File name of the model, without the server's directories.
+    fn name(&self) -> String {
+        model_name(&self.path)
+    }
+
+    fn current(&self) -> Arc<WhisperContext> {
+        self.ctx.read().unwrap().clone()
+    }
+
+    async fn reload(&self) -> Result<()> {
+        let path = self.path.clone();
+        let ctx =
+            tokio::task::spawn_blocking(move || load_context(&path)).await??;
+        *self.ctx.write().unwrap() = Arc::new(ctx);
+        info!("Reloaded whisper model: {}", self.path);
+        Ok(())
+    }
+}
+
+fn model_name(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn load_context(path: &str) -> Result<WhisperContext> {
+    info!("Loading whisper model: {}", path);
+    let mut params = WhisperContextParameters::default();
+    params.flash_attn(true);
+    #[cfg(not(feature = "vulkan"))]
+    info!("Running on CPU");
+    #[cfg(feature = "vulkan")]
+    {
+        info!("Running with GPU acceleration (Vulkan)");
+        params.use_gpu(true);
+    }
+    Ok(WhisperContext::new_with_params(path, params)?)
 }
 
 #[tokio::main]
@@ -68,20 +356,20 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
     let addr: SocketAddr = format!("{}:{}", args.address, args.port).parse()?;
-    info!("Loading whisper model: {}", args.model);
-
-    let ctx = {
-        let mut params = WhisperContextParameters::default();
-        params.flash_attn(true);
-        #[cfg(not(feature = "vulkan"))]
-        info!("Running on CPU");
-        #[cfg(feature = "vulkan")]
-        {
-            info!("Running with GPU acceleration (Vulkan)");
-            params.use_gpu(true);
+    let model = Arc::new(Model::load(&args.model)?);
+    let mut models = HashMap::from([(model.name(), model.clone())]);
+    if let Some(ref dir) = args.model_dir {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if !name.ends_with(".bin") || models.contains_key(&*name) {
+                continue;
+            }
+            let extra = Model::load(&path.to_string_lossy())?;
+            models.insert(extra.name(), Arc::new(extra));
         }
-        Arc::new(WhisperContext::new_with_params(&args.model, params)?)
-    };
+        info!("Models available: {:?}", models.keys().collect::<Vec<_>>());
+    }
 
     let expected_token = match &args.token_file {
         Some(path) => {
@@ -123,33 +411,172 @@ async fn main() -> Result<()> {
         dynamic_audio_ctx: args.dynamic_audio_ctx,
         temperature_inc: args.temperature_inc,
         entropy_thold: args.entropy_thold,
+        logprob_thold: args.logprob_thold,
+        no_speech_thold: args.no_speech_thold,
+        min_token_prob: args.min_token_prob,
         reinit_state: args.reinit_state,
+        agc: args.agc,
+        max_audio_rate: args.max_audio_rate,
+        min_audio_cs: args.min_audio_cs,
+        hallucination_repeats: args.hallucination_repeats,
+        hallucination_no_speech: args.hallucination_no_speech,
+        max_window_cs: args.max_window_cs,
+        dump_audio_dir: args.dump_audio_dir,
+        preview_best_of: args.preview_best_of,
+    };
+
+    // sessions finalize when this turns true, see SHUTDOWN_GRACE
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = Arc::new(ServerOpts {
+        expected_token,
+        sampling_strategy,
+        transcribe_opts,
+        transcript_dir: args.transcript_dir,
+        allow_audio_echo: args.allow_audio_echo,
+        memory_budget: args.memory_budget_mb.map(MemoryBudget::new),
+        strict_config: args.strict_config,
+        configure_timeout: args.configure_timeout_secs.map(Duration::from_secs),
+        idle_timeout: args.session_idle_timeout_secs.map(Duration::from_secs),
+        warm_pool: (args.warm_states > 0).then(|| {
+            Arc::new(WarmPool::new(args.warm_states, args.warm_up_ms))
+        }),
+        preemptible: args.preemptible,
+        shutdown: shutdown_rx,
+        models,
+        fast_preview_model: match args.fast_preview_model {
+            Some(ref path) => Some(Model::load(path)?),
+            None => None,
+        },
+    });
+    if let Some(ref pool) = server.warm_pool {
+        pool.refill(model.current());
+    }
+
+    let mut rate_limiter = args.rate_limit.map(|per_minute| {
+        info!(
+            "Rate limiting to {}/min per IP, burst {}",
+            per_minute, args.rate_limit_burst
+        );
+        RateLimiter::new(per_minute, args.rate_limit_burst)
+    });
+
+    // clap makes sure that both or neither are given
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!("Serving TLS with {}", cert.display());
+            Some(tls::load_acceptor(&cert, &key)?)
+        }
+        _ => None,
     };
 
+    if let Some(port) = args.metrics_port {
+        let metrics_addr = SocketAddr::new(addr.ip(), port);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics_addr).await {
+                error!("Metrics server error: {}", e);
+            }
+        });
+    }
+
     info!("Listening on {}", addr);
     let listener = TcpListener::bind(addr).await?;
-    while let Ok((stream, peer_addr)) = listener.accept().await {
+    let connection_limit = args.max_connections.map(|n| {
+        info!("Limiting to {} concurrent connections", n);
+        Arc::new(Semaphore::new(n as usize))
+    });
+    let mut connections = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        while connections.try_join_next().is_some() {} // reap finished ones
         stream.set_nodelay(true)?;
+        let tls = tls.clone();
+        if let Some(ref mut limiter) = rate_limiter
+            && !limiter.check(peer_addr.ip())
+        {
+            info!("Rate limit exceeded, rejecting {}", peer_addr);
+            tokio::spawn(reject_connection(
+                stream,
+                tls,
+                "RATE_LIMITED",
+                "rate limit exceeded",
+            ));
+            continue;
+        }
+        let permit = match connection_limit {
+            Some(ref limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    info!("Too many connections, rejecting {}", peer_addr);
+                    tokio::spawn(reject_connection(
+                        stream,
+                        tls,
+                        "TOO_MANY_CONNECTIONS",
+                        "server is at its connection limit",
+                    ));
+                    continue;
+                }
+            },
+            None => None,
+        };
         info!("Connection from {}", peer_addr);
-        let ctx = ctx.clone();
-        let exp_token = expected_token.clone();
-        let strategy = sampling_strategy.clone();
-        let opts = transcribe_opts.clone();
-        tokio::spawn(async move {
-            if let Err(e) =
-                handle_connection(stream, ctx, exp_token, strategy, opts).await
-            {
+        let model = model.clone();
+        let server = server.clone();
+        connections.spawn(async move {
+            let _permit = permit; // released when the connection ends
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(s) => handle_connection(s, model, server).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_connection(stream, model, server).await,
+            };
+            if let Err(e) = result {
                 error!("Connection error: {}", e);
             }
         });
     }
+
+    // let the sessions finish with a final transcription and a Close
+    info!("Shutting down, finalizing {} sessions", connections.len());
+    let _ = shutdown_tx.send(true);
+    let mut drained = 0;
+    let grace = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {
+            drained += 1;
+        }
+    });
+    if grace.await.is_err() {
+        warn!("{} sessions did not finish in time", connections.len());
+    }
+    info!("Drained {} sessions", drained);
     Ok(())
 }
 
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut term = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
+
 macro_rules! bail {
-    ($ws_sender:expr, $($arg:tt)*) => {{
+    ($ws_sender:expr, $code:expr, $($arg:tt)*) => {{
         let msg = format!($($arg)*);
-        let m = ServerMessage::Error { message: msg.clone() };
+        let m = ServerMessage::Error {
+            message: msg.clone(),
+            code: Some($code.to_string()),
+        };
         let m = serde_json::to_string(&m).unwrap();
         let _ = $ws_sender.send(Message::Text(m)).await;
         let _ = $ws_sender.send(Message::Close(None)).await;
@@ -157,6 +584,219 @@ macro_rules! bail {
     }};
 }
 
+// Send a message to the client and to any additional output consumers
+macro_rules! send {
+    ($ws_sender:expr, $outputs:expr, $compress:expr, $msg:expr) => {{
+        let msg: &ServerMessage = &$msg;
+        $ws_sender.send(encode(msg, $compress)?).await?;
+        if $outputs.receiver_count() > 0 {
+            let _ = $outputs.send(msg.clone());
+        }
+    }};
+}
+
+/// A server message as a WebSocket frame, see shared_protocol::ZSTD_FRAME_TAG.
+fn encode(msg: &ServerMessage, compress: bool) -> Result<Message> {
+    let json = serde_json::to_string(msg)?;
+    if !compress {
+        return Ok(Message::Text(json));
+    }
+    let mut frame = vec![ZSTD_FRAME_TAG];
+    frame.extend(zstd::encode_all(json.as_bytes(), ZSTD_LEVEL)?);
+    Ok(Message::Binary(frame))
+}
+
+/// Sends an Error and a Close to a client that won't be served.
+async fn reject_connection(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    code: &'static str,
+    reason: &'static str,
+) -> Result<()> {
+    match tls {
+        Some(tls) => {
+            let stream = tls.accept(stream).await?;
+            reject_websocket(stream, code, reason).await
+        }
+        None => reject_websocket(stream, code, reason).await,
+    }
+}
+
+async fn reject_websocket<S>(stream: S, code: &str, reason: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
+    let (mut ws_sender, _) = ws_stream.split();
+    bail!(ws_sender, code, "{}", reason);
+}
+
+/// Languages the model can be configured with, "auto" not included.
+fn supported_languages(ctx: &WhisperContext) -> Vec<String> {
+    if !ctx.is_multilingual() {
+        return vec!["en".to_string()];
+    }
+    (0..=whisper_rs::get_lang_max_id())
+        .filter_map(whisper_rs::get_lang_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Awaits `future`, giving up after `timeout` if there is one.
+async fn within<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, tokio::time::error::Elapsed> {
+    match timeout {
+        Some(t) => tokio::time::timeout(t, future).await,
+        None => Ok(future.await),
+    }
+}
+
+/// Runs `f` on `value` on the blocking pool, so that a whisper decode does
+/// not hold up the other connections served by the same worker; `value` is
+/// handed back along with the result.
+fn off_executor<T, R, F>(mut value: T, f: F) -> JoinHandle<(T, R)>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> R + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let result = f(&mut value);
+        (value, result)
+    })
+}
+
+/// Resolves once a session has received nothing since `last_input` for
+/// `timeout`, never without a timeout.
+async fn idle_expired(timeout: Option<Duration>, last_input: Instant) {
+    match timeout {
+        Some(t) => {
+            let deadline = tokio::time::Instant::from_std(last_input + t);
+            tokio::time::sleep_until(deadline).await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The next message to drain, without waiting: those queued in `pending`
+/// while whisper ran come first, then whatever arrived since. None if
+/// nothing did, Some(None) once the stream has ended.
+fn next_ready<S, E>(
+    pending: &mut VecDeque<Message>,
+    mut receiver: Pin<&mut S>,
+) -> Option<Option<Result<Message, E>>>
+where
+    S: Stream<Item = Result<Message, E>>,
+{
+    match pending.pop_front() {
+        Some(msg) => Some(Some(Ok(msg))),
+        None => receiver.next().now_or_never(),
+    }
+}
+
+/// What `DecodeWatch::until_done` returned on.
+enum Watched<D, P> {
+    Decoded(D),
+    Previewed(P),
+}
+
+/// Watches the client while whisper decodes.
+struct DecodeWatch<'a> {
+    abort: &'a AtomicBool, // of the decode, see Session::abort_handle
+    pending: &'a mut VecDeque<Message>, // input to drain after the decode
+    preemptible: bool,     // aborts for newer input, see --preemptible
+    preempted: bool,
+    peer_gone: bool,
+}
+
+impl DecodeWatch<'_> {
+    /// Queues the input arriving until `decode`, or `preview` decoded
+    /// alongside it, finishes. Aborts the decode once the client has left,
+    /// or when preemptible and an Advance supersedes it.
+    async fn until_done<S, E, D, P>(
+        &mut self,
+        decode: &mut JoinHandle<D>,
+        mut preview: Option<&mut JoinHandle<P>>,
+        mut receiver: Pin<&mut S>,
+    ) -> Watched<Result<D, JoinError>, Result<P, JoinError>>
+    where
+        S: Stream<Item = Result<Message, E>>,
+    {
+        loop {
+            tokio::select! {
+                joined = &mut *decode => return Watched::Decoded(joined),
+                joined = async { preview.as_mut().unwrap().await },
+                    if preview.is_some() => return Watched::Previewed(joined),
+                msg = receiver.next(), if !self.peer_gone => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        info!("client left, aborting transcription");
+                        self.abort.store(true, Ordering::Relaxed);
+                        self.pending.push_back(Message::Close(None));
+                        self.peer_gone = true;
+                    }
+                    Some(Ok(msg)) => {
+                        if self.preemptible
+                            && !self.preempted
+                            && supersedes(&msg, self.pending)
+                        {
+                            info!("newer input arrived, preempting");
+                            self.abort.store(true, Ordering::Relaxed);
+                            self.preempted = true;
+                        }
+                        self.pending.push_back(msg);
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// What `msg` carries that is ignored once the input has ended: audio or
+/// an Advance, see ClientMessage::EndOfStream.
+fn late_input(msg: &Message) -> Option<&'static str> {
+    match msg {
+        Message::Binary(_) => Some("audio"),
+        Message::Text(text) => matches!(
+            serde_json::from_str(text),
+            Ok(ClientMessage::Advance { .. })
+        )
+        .then_some("Advance"),
+        _ => None,
+    }
+}
+
+/// Whether `msg`, arriving behind `pending` while whisper runs, makes the
+/// running preview stale: an Advance, or enough audio piling up.
+fn supersedes(msg: &Message, pending: &VecDeque<Message>) -> bool {
+    match msg {
+        Message::Text(text) => matches!(
+            serde_json::from_str(text),
+            Ok(ClientMessage::Advance { .. })
+        ),
+        Message::Binary(_) => {
+            let queued = pending.iter().filter(|m| m.is_binary()).count();
+            queued + 1 >= PREEMPT_FRAMES
+        }
+        _ => false,
+    }
+}
+
+/// Fields of a Configure message that serde would silently ignore.
+fn unknown_config_fields(text: &str) -> Vec<String> {
+    let known = serde_json::to_value(Config::default()).unwrap();
+    let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(text)
+    else {
+        return Vec::new();
+    };
+    fields
+        .keys()
+        .filter(|k| *k != "type" && known.get(k).is_none())
+        .cloned()
+        .collect()
+}
+
 fn normalize_for_comparison(s: &str) -> String {
     s.chars()
         .filter(|c| c.is_alphanumeric() || c.is_whitespace())
@@ -200,101 +840,326 @@ fn compare_segments(
     (exact_match, n_matching_tokens)
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    ctx: Arc<WhisperContext>,
-    expected_token: Option<String>,
-    sampling_strategy: SamplingStrategy,
-    opts: TranscribeOpts,
+/// Writes every message of a session as a line of JSON.
+async fn persist_transcript(
+    mut outputs: broadcast::Receiver<ServerMessage>,
+    path: PathBuf,
 ) -> Result<()> {
+    let mut file = tokio::fs::File::create(&path).await?;
+    info!("Writing transcript to {}", path.display());
+    loop {
+        match outputs.recv().await {
+            Ok(msg) => {
+                let mut line = serde_json::to_vec(&msg)?;
+                line.push(b'\n');
+                file.write_all(&line).await?;
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("transcript {} lost {} messages", path.display(), n);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Serves a client over a plain TCP or a TLS stream.
+async fn handle_connection<S>(
+    stream: S,
+    model: Arc<Model>,
+    server: Arc<ServerOpts>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _active = METRICS.connection_opened();
     let ws_stream = tokio_tungstenite::accept_async(stream).await?;
     let (mut ws_sender, ws_receiver) = ws_stream.split();
     let ws_receiver = ws_receiver.peekable();
     futures_util::pin_mut!(ws_receiver);
 
     // First wait for the mandatory Configure message:
-    let (
-        token,
-        language,
-        context,
-        max_len,
-        max_tokens,
-        single_segment,
-        max_initial_ts,
-        no_preview,
-        two_stroke,
-    ) = match ws_receiver.as_mut().next().await {
+    let timeout = server.configure_timeout;
+    let Ok(first) = within(timeout, ws_receiver.next()).await else {
+        bail!(
+            ws_sender,
+            "CONFIGURE_TIMEOUT",
+            "no Configure within {}s",
+            timeout.unwrap_or_default().as_secs()
+        );
+    };
+    let config = match first {
         Some(Ok(Message::Text(text))) => {
             match serde_json::from_str::<ClientMessage>(&text) {
-                Ok(ClientMessage::Configure {
-                    token,
-                    language,
-                    context,
-                    max_len,
-                    max_tokens,
-                    single_segment,
-                    max_initial_ts,
-                    no_preview,
-                    two_stroke,
-                }) => (
-                    token,
-                    language,
-                    context,
-                    max_len,
-                    max_tokens,
-                    single_segment,
-                    max_initial_ts,
-                    no_preview,
-                    two_stroke,
+                Ok(ClientMessage::Configure(config)) => {
+                    let unknown = unknown_config_fields(&text);
+                    if server.strict_config && !unknown.is_empty() {
+                        bail!(
+                            ws_sender,
+                            "BAD_CONFIGURE",
+                            "unknown Configure fields: {}",
+                            unknown.join(", ")
+                        );
+                    }
+                    config
+                }
+                Ok(ClientMessage::ReloadModel { token, model: name }) => {
+                    match (&server.expected_token, token) {
+                        (Some(expected), Some(t)) if *expected == t => (),
+                        (None, _) => bail!(
+                            ws_sender,
+                            "AUTH_FAILED",
+                            "reload needs a token"
+                        ),
+                        _ => bail!(ws_sender, "AUTH_FAILED", "wrong API token"),
+                    }
+                    let model = match name.as_deref() {
+                        None => model,
+                        Some(name) => match server.models.get(name) {
+                            Some(m) => m.clone(),
+                            None => bail!(
+                                ws_sender,
+                                "UNKNOWN_MODEL",
+                                "unknown model {:?}",
+                                name
+                            ),
+                        },
+                    };
+                    if let Err(e) = model.reload().await {
+                        bail!(
+                            ws_sender,
+                            "RELOAD_FAILED",
+                            "error reloading model: {}",
+                            e
+                        );
+                    }
+                    let reloaded = ServerMessage::ModelReloaded {
+                        model: model.name(),
+                    };
+                    let json = serde_json::to_string(&reloaded)?;
+                    ws_sender.send(Message::Text(json)).await?;
+                    ws_sender.send(Message::Close(None)).await?;
+                    return Ok(());
+                }
+                Ok(_) => bail!(
+                    ws_sender,
+                    "BAD_CONFIGURE",
+                    "first message must be Configure"
+                ),
+                Err(e) => bail!(
+                    ws_sender,
+                    "BAD_CONFIGURE",
+                    "failed to parse Configure : {}",
+                    e
                 ),
-                Ok(_) => bail!(ws_sender, "first message must be Configure"),
-                Err(e) => bail!(ws_sender, "failed to parse Configure : {}", e),
             }
         }
-        Some(Ok(_)) => bail!(ws_sender, "must send Configure first"),
-        Some(Err(e)) => bail!(ws_sender, "pre-configure error {}", e),
-        None => bail!(ws_sender, "connection closed before Configure"),
+        Some(Ok(_)) => {
+            bail!(ws_sender, "BAD_CONFIGURE", "must send Configure first")
+        }
+        Some(Err(e)) => {
+            bail!(ws_sender, "WEBSOCKET_ERROR", "pre-configure error {}", e)
+        }
+        None => bail!(
+            ws_sender,
+            "CONNECTION_CLOSED",
+            "connection closed before Configure"
+        ),
     };
 
     // Then check the token, if needed:
-    if let Some(ref expected) = expected_token {
-        match token {
+    if let Some(ref expected) = server.expected_token {
+        match config.token {
             Some(ref t) if t == expected => (),
-            Some(_) => bail!(ws_sender, "wrong API token"),
-            None => bail!(ws_sender, "missing API token"),
+            Some(_) => bail!(ws_sender, "AUTH_FAILED", "wrong API token"),
+            None => bail!(ws_sender, "AUTH_FAILED", "missing API token"),
         }
     }
+    if let Some(v) = config.protocol_version
+        && v != PROTOCOL_VERSION
+    {
+        bail!(
+            ws_sender,
+            "UNSUPPORTED_VERSION",
+            "protocol version {} not supported, this server speaks {}",
+            v,
+            PROTOCOL_VERSION
+        );
+    }
+    let compress = match config.compression.as_deref() {
+        None => false,
+        Some("zstd") if config.audio_echo.unwrap_or(false) => bail!(
+            ws_sender,
+            "BAD_CONFIGURE",
+            "compression can't be combined with audio_echo"
+        ),
+        Some("zstd") => true,
+        Some(other) => bail!(
+            ws_sender,
+            "BAD_CONFIGURE",
+            "unsupported compression {:?}",
+            other
+        ),
+    };
+
+    // Every message sent to the client is also fanned out to these
+    let (outputs, _) = broadcast::channel::<ServerMessage>(OUTPUTS_CAPACITY);
+
+    // From here on, errors go out like any other message, see send!
+    macro_rules! bail {
+        ($ws_sender:expr, $code:expr, $($arg:tt)*) => {{
+            let msg = format!($($arg)*);
+            let m = ServerMessage::Error {
+                message: msg.clone(),
+                code: Some($code.to_string()),
+            };
+            if let Ok(frame) = encode(&m, compress) {
+                let _ = $ws_sender.send(frame).await;
+            }
+            if outputs.receiver_count() > 0 {
+                let _ = outputs.send(m);
+            }
+            let _ = $ws_sender.send(Message::Close(None)).await;
+            return Err(anyhow::anyhow!(msg));
+        }};
+    }
+
+    if config.audio_echo.unwrap_or(false) && !server.allow_audio_echo {
+        bail!(
+            ws_sender,
+            "NOT_ALLOWED",
+            "audio echo is not allowed on this server"
+        );
+    }
+
     // Then configure the transcription session:
-    info!("Configured: language={:?}, context={:?}", language, context);
+    info!(
+        "Configured: language={:?}, context={:?}",
+        config.language, config.context
+    );
+    let default_model = model.clone();
+    let model = match config.model.as_deref() {
+        None => model,
+        Some(name) => match server.models.get(name) {
+            Some(m) => m.clone(),
+            None => {
+                bail!(ws_sender, "UNKNOWN_MODEL", "unknown model {:?}", name)
+            }
+        },
+    };
+    let ctx = model.current();
+    let warm_state = match server.warm_pool {
+        // the pool only keeps states of the default model
+        Some(ref pool) if Arc::ptr_eq(&model, &default_model) => {
+            let state = pool.take(&ctx);
+            pool.refill(ctx.clone());
+            state
+        }
+        _ => None,
+    };
     let mut session = match Session::new(
         ctx,
-        language,
-        context,
-        max_len,
-        max_tokens,
-        single_segment,
-        max_initial_ts,
-        sampling_strategy,
-        opts,
+        warm_state,
+        &config,
+        server.sampling_strategy.clone(),
+        server.transcribe_opts.clone(),
     ) {
         Ok(s) => s,
-        Err(e) => bail!(ws_sender, "error creating session: {}", e),
+        Err(e) => {
+            bail!(ws_sender, "BAD_CONFIGURE", "error creating session: {}", e)
+        }
     };
 
-    let two_stroke = two_stroke.unwrap_or(false);
+    // Decodes the previews with its own state, concurrently with `session`
+    let mut previewer = match server.fast_preview_model {
+        Some(ref m) if config.fast_preview.unwrap_or(false) => {
+            match PreviewModel::new(m.current()) {
+                Ok(p) => Some(p),
+                Err(e) => bail!(
+                    ws_sender,
+                    "BAD_CONFIGURE",
+                    "error creating preview state: {}",
+                    e
+                ),
+            }
+        }
+        _ => None,
+    };
+
+    if let Some(ref dir) = server.transcript_dir {
+        let path = dir.join(format!("{}.jsonl", uuid::Uuid::new_v4()));
+        let rx = outputs.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = persist_transcript(rx, path).await {
+                error!("Transcript persistence error: {}", e);
+            }
+        });
+    }
+
+    let hello = ServerMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        supported_languages: supported_languages(&model.current()),
+        model_name: model.name(),
+    };
+    send!(ws_sender, outputs, compress, hello);
+
+    if config.config_ack.unwrap_or(true) {
+        let applied = serde_json::to_value(session.applied_config(&config))?;
+        send!(
+            ws_sender,
+            outputs,
+            compress,
+            ServerMessage::ConfigAck { applied }
+        );
+    }
+
+    let no_preview = config.no_preview.unwrap_or(false);
+    let two_stroke = config.two_stroke.unwrap_or(false);
+    let stats = config.stats.unwrap_or(false);
+    let interim_words = config.interim_words.unwrap_or(false);
+    let long_form = config.long_form.unwrap_or(false);
+    let final_summary = config.final_summary.unwrap_or(false);
+    let session_export = config.session_export.unwrap_or(false);
+    let uncertainty = config.uncertainty.unwrap_or(false);
+    let fast_preview = config.fast_preview.unwrap_or(false);
+    let pcm_input = config.audio_format.as_deref() == Some("pcm_s16le");
 
-    // Drain all pending WebSocket messages (audio, advance, EOS)
+    // Messages received while whisper was running, to be drained first
+    let mut pending: VecDeque<Message> = VecDeque::new();
+    let mut last_input = Instant::now(); // see idle_expired
+
+    // Drain all pending WebSocket messages (audio, advance, EOS).
+    // Strictly in arrival order, messages queued in `pending` while whisper
+    // ran included: an Advance only sees the audio sent before it.
     macro_rules! drain {
-        ($ws_receiver:expr, $ws_sender:expr, $session:expr, $finalized:expr) => {
+        ($ws_receiver:expr, $ws_sender:expr, $session:expr, $finalized:expr, $pending:expr) => {
             loop {
-                match $ws_receiver.as_mut().next().now_or_never() {
+                let next = next_ready(&mut $pending, $ws_receiver.as_mut());
+                if let Some(Some(Ok(_))) = next {
+                    last_input = Instant::now();
+                }
+                if $finalized
+                    && let Some(Some(Ok(ref msg))) = next
+                    && let Some(what) = late_input(msg)
+                {
+                    info!("ignoring {} after end of stream", what);
+                    continue;
+                }
+                match next {
                     Some(Some(Ok(msg))) => match msg {
                         Message::Text(text) => {
                             match serde_json::from_str::<ClientMessage>(&text) {
-                                Ok(ClientMessage::Configure { .. }) => bail!(
+                                Ok(ClientMessage::Configure(_)) => bail!(
                                     $ws_sender,
+                                    "PROTOCOL_ERROR",
                                     "Configure sent after session started"
                                 ),
+                                Ok(ClientMessage::ReloadModel { .. }) => bail!(
+                                    $ws_sender,
+                                    "PROTOCOL_ERROR",
+                                    "ReloadModel must be the first message"
+                                ),
                                 Ok(ClientMessage::Advance {
                                     timestamp_cs,
                                     context,
@@ -302,7 +1167,12 @@ async fn handle_connection(
                                     if let Err(e) =
                                         $session.advance(timestamp_cs, context)
                                     {
-                                        bail!($ws_sender, "advance failed: {}", e);
+                                        bail!(
+                                            $ws_sender,
+                                            "ADVANCE_FAILED",
+                                            "advance failed: {}",
+                                            e
+                                        );
                                     };
                                     let time_s = timestamp_cs as f64 / 100.;
                                     info!("advanced to {:.2}s", time_s);
@@ -311,105 +1181,582 @@ async fn handle_connection(
                                     info!("end of audio stream");
                                     $finalized = true;
                                 }
+                                Ok(ClientMessage::Reconfigure {
+                                    language,
+                                    context,
+                                }) => {
+                                    // not fatal, the session goes on unchanged
+                                    if let Err(e) =
+                                        $session.reconfigure(language, context)
+                                    {
+                                        let m = ServerMessage::Error {
+                                            message: format!(
+                                                "reconfigure failed: {}",
+                                                e
+                                            ),
+                                            code: Some(
+                                                "BAD_RECONFIGURE".into(),
+                                            ),
+                                        };
+                                        send!($ws_sender, outputs, compress, m);
+                                    }
+                                }
+                                Ok(ClientMessage::SpeakerHints { ranges }) => {
+                                    if let Err(e) =
+                                        $session.add_speaker_hints(ranges)
+                                    {
+                                        bail!(
+                                            $ws_sender,
+                                            "BAD_SPEAKER_HINTS",
+                                            "speaker hints rejected: {}",
+                                            e
+                                        );
+                                    }
+                                }
+                                Ok(ClientMessage::Pause) => {
+                                    info!("audio paused");
+                                    $session.set_paused(true);
+                                }
+                                Ok(ClientMessage::Resume) => {
+                                    info!("audio resumed");
+                                    $session.set_paused(false);
+                                }
+                                Ok(ClientMessage::PacketLost { count }) => {
+                                    if let Err(e) = $session.packets_lost(count)
+                                    {
+                                        bail!(
+                                            $ws_sender,
+                                            "DECODE_ERROR",
+                                            "packet loss rejected: {}",
+                                            e
+                                        );
+                                    }
+                                }
+                                Ok(ClientMessage::Ping { nonce }) => {
+                                    let pong = ServerMessage::Pong { nonce };
+                                    send!($ws_sender, outputs, compress, pong);
+                                }
+                                Ok(ClientMessage::GetState) => {
+                                    let state = $session.state();
+                                    send!($ws_sender, outputs, compress, state);
+                                }
                                 Err(e) => {
-                                    bail!($ws_sender, "cannot parse message: {}", e)
+                                    bail!(
+                                        $ws_sender,
+                                        "PROTOCOL_ERROR",
+                                        "cannot parse message: {}",
+                                        e
+                                    )
                                 }
                             }
                         }
                         Message::Binary(data) => {
-                            if let Err(e) = $session.decode_and_append_opus(&data) {
-                                bail!($ws_sender, "error decoding Opus: {}", e);
+                            let appended = if pcm_input {
+                                $session.append_pcm(&data)
+                            } else {
+                                $session.decode_and_append_opus(&data)
+                            };
+                            if let Err(e) = appended {
+                                bail!(
+                                    $ws_sender,
+                                    "DECODE_ERROR",
+                                    "error decoding audio: {}",
+                                    e
+                                );
+                            }
+                            if let Some(pcm) = $session.take_echo() {
+                                $ws_sender.send(Message::Binary(pcm)).await?;
                             }
                         }
                         Message::Ping(data) => {
                             $ws_sender.send(Message::Pong(data)).await?;
                         }
                         Message::Pong(_) | Message::Frame(_) => {}
-                        Message::Close(_) => bail!($ws_sender, "connection closed"),
+                        Message::Close(_) => bail!(
+                            $ws_sender,
+                            "CONNECTION_CLOSED",
+                            "connection closed"
+                        ),
                     },
                     Some(Some(Err(e))) => {
-                        bail!($ws_sender, "websocket error: {}", e)
+                        bail!(
+                            $ws_sender,
+                            "WEBSOCKET_ERROR",
+                            "websocket error: {}",
+                            e
+                        )
                     }
-                    Some(None) => bail!($ws_sender, "connection closed"),
+                    Some(None) => bail!(
+                        $ws_sender,
+                        "CONNECTION_CLOSED",
+                        "connection closed"
+                    ),
                     None => break,
                 }
             }
         };
     }
 
+    // Sends the result of a `previewer` decode, handing the model back
+    macro_rules! preview_done {
+        ($joined:expr) => {{
+            let (returned, preview) = $joined?;
+            previewer = Some(returned);
+            match preview {
+                Ok(Some(msg)) => send!(ws_sender, outputs, compress, msg),
+                Ok(None) => {}
+                Err(e) => error!("fast preview error: {}", e),
+            }
+        }};
+    }
+
     // Finally, enter the normal drain-transcribe loop:
     let mut finalized = false;
+    let mut last_full: Option<Instant> = None; // see FULL_INTERVAL
+    let mut preempted = false; // last transcription aborted for newer input
+    let mut shutdown = server.shutdown.clone();
     loop {
-        drain!(ws_receiver, ws_sender, session, finalized);
+        drain!(ws_receiver, ws_sender, session, finalized, pending);
+        if !finalized && *shutdown.borrow_and_update() {
+            info!("server shutting down, finalizing session");
+            finalized = true;
+        }
 
         // transcribe
-        if no_preview.unwrap_or(false) && !finalized {
-            ws_receiver.as_mut().peek().await;
+        if no_preview && !finalized {
+            if pending.is_empty() {
+                tokio::select! {
+                    _ = ws_receiver.as_mut().peek() => {}
+                    _ = shutdown.changed() => {}
+                    _ = idle_expired(server.idle_timeout, last_input) => bail!(
+                        ws_sender,
+                        "IDLE_TIMEOUT",
+                        "no input for {}s",
+                        last_input.elapsed().as_secs()
+                    ),
+                }
+            }
             continue;
         }
-        match session.transcribe(finalized) {
+        // With a separate model, previews are decoded at every iteration,
+        // alongside the full transcription when it is due
+        let mut preview_task = None;
+        if let Some(p) = previewer.take().filter(|_| !finalized) {
+            let job = session.preview_job(Vec::new());
+            let task = off_executor(p, move |p| p.run(&job));
+            let since_full = last_full.map_or(FULL_INTERVAL, |t| t.elapsed());
+            if since_full < FULL_INTERVAL {
+                preview_done!(task.await);
+                if pending.is_empty() {
+                    tokio::select! {
+                        _ = ws_receiver.as_mut().peek() => {}
+                        _ = shutdown.changed() => {}
+                        _ = tokio::time::sleep(FULL_INTERVAL - since_full) => {}
+                        _ = idle_expired(server.idle_timeout, last_input) => bail!(
+                            ws_sender,
+                            "IDLE_TIMEOUT",
+                            "no input for {}s",
+                            last_input.elapsed().as_secs()
+                        ),
+                    }
+                }
+                continue;
+            }
+            preview_task = Some(task);
+        }
+        let reservation = match server.memory_budget {
+            Some(ref budget) => {
+                Some(budget.reserve(session.transcription_bytes()).await?)
+            }
+            None => None,
+        };
+        if fast_preview && server.fast_preview_model.is_none() && !finalized {
+            let (returned, preview) =
+                off_executor(session, Session::fast_preview).await?;
+            session = returned;
+            match preview {
+                Ok(Some(msg)) => send!(ws_sender, outputs, compress, msg),
+                Ok(None) => {}
+                Err(e) => error!("fast preview error: {}", e),
+            }
+        }
+        // Run whisper off the executor, so that we notice the client leaving
+        // in the meantime and abort the decode instead of finishing it
+        let abort = session.abort_handle();
+        abort.store(false, Ordering::Relaxed);
+        // never twice in a row, so that slow decodes still deliver results
+        let preemptible = server.preemptible && !finalized && !preempted;
+        last_full = Some(Instant::now());
+        let mut preview_pending = preview_task.is_some();
+        let mut transcription = off_executor(session, move |session| {
+            if finalized && long_form {
+                session.transcribe_long_form()
+            } else {
+                session.transcribe(finalized)
+            }
+        });
+        let mut watch = DecodeWatch {
+            abort: &abort,
+            pending: &mut pending,
+            preemptible,
+            preempted: false,
+            peer_gone: false,
+        };
+        let result = loop {
+            let preview = preview_task.as_mut().filter(|_| preview_pending);
+            let receiver = ws_receiver.as_mut();
+            match watch
+                .until_done(&mut transcription, preview, receiver)
+                .await
+            {
+                Watched::Decoded(joined) => {
+                    let (returned, result) = joined?;
+                    session = returned;
+                    break result;
+                }
+                Watched::Previewed(joined) => {
+                    preview_pending = false;
+                    preview_done!(joined);
+                }
+            }
+        };
+        preempted = watch.preempted;
+        let peer_gone = watch.peer_gone;
+        if let Some(task) = preview_task.filter(|_| preview_pending) {
+            preview_done!(task.await);
+        }
+        match result {
             Ok(Some(msg)) => {
-                let json = serde_json::to_string(&msg)?;
-                ws_sender.send(Message::Text(json)).await?;
+                send!(ws_sender, outputs, compress, msg);
+
+                if stats {
+                    send!(ws_sender, outputs, compress, session.stats());
+                }
+
+                if let Some(m) = session.take_quality_changed() {
+                    send!(ws_sender, outputs, compress, m);
+                }
+
+                if let Some(top_k) = config.language_probabilities {
+                    // an extra encoder pass, off the executor like the rest
+                    let (returned, probabilities) =
+                        off_executor(session, move |session| {
+                            session.language_probabilities(top_k)
+                        })
+                        .await?;
+                    session = returned;
+                    match probabilities {
+                        Ok(Some(m)) => send!(ws_sender, outputs, compress, m),
+                        Ok(None) => {} // language is fixed
+                        Err(e) => error!("language detection error: {}", e),
+                    }
+                }
+
+                if uncertainty
+                    && !finalized
+                    && let Some(m) = Session::uncertainty(&msg)
+                {
+                    send!(ws_sender, outputs, compress, m);
+                }
+
+                if final_summary
+                    && finalized
+                    && let Some(summary) = session.final_summary(&msg)
+                {
+                    send!(ws_sender, outputs, compress, summary);
+                }
+
+                if session_export && finalized {
+                    let model_name = model.name();
+                    if let Some(export) =
+                        session.session_export(&msg, model_name)
+                    {
+                        send!(ws_sender, outputs, compress, export);
+                    }
+                }
+                session.track_export(&msg);
+
+                if interim_words
+                    && let Some(words) = session.words_appended(&msg)
+                {
+                    send!(ws_sender, outputs, compress, words);
+                }
 
                 // Two-stroke: re-transcribe from second-to-last segment end
-                if two_stroke && !finalized {
-                    if let ServerMessage::Transcription {
+                if two_stroke
+                    && !finalized
+                    && let ServerMessage::Transcription {
                         ref complete,
                         advance_cs: tx_advance_cs,
                         ..
                     } = msg
-                    {
-                        if complete.len() >= 2 {
-                            let last_index = complete.len() - 1;
-                            let second_to_last = &complete[last_index - 1];
-                            let last = &complete[last_index];
-                            let retranscribe_from_cs = second_to_last.end_cs;
-
-                            // Drain again to pick up any audio that arrived during transcription
-                            drain!(ws_receiver, ws_sender, session, finalized);
-
-                            // If EOS arrived during transcription, skip the
-                            // suggestion pass — loop back for a finalized transcription
-                            if finalized {
-                                continue;
-                            }
+                    && complete.len() >= 2
+                {
+                    let last_index = complete.len() - 1;
+                    let second_to_last = &complete[last_index - 1];
+                    let last = &complete[last_index];
+                    let retranscribe_from_cs = second_to_last.end_cs;
 
-                            match session.transcribe_from(retranscribe_from_cs, finalized) {
-                                Ok(retranscribed_segments) => {
-                                    let (exact_match, n_matching_tokens) =
-                                        compare_segments(last, &retranscribed_segments);
-                                    let suggestion = ServerMessage::AdvanceSuggestion {
-                                        advance_cs: tx_advance_cs,
-                                        timestamp_cs: last.end_cs,
-                                        segments: retranscribed_segments,
-                                        original_last_segment: last.clone(),
-                                        exact_match,
-                                        n_matching_tokens,
-                                    };
-                                    let json = serde_json::to_string(&suggestion)?;
-                                    ws_sender.send(Message::Text(json)).await?;
-                                }
-                                Err(e) => {
-                                    error!("two-stroke retranscription error: {}", e);
-                                }
-                            }
+                    // Drain again to pick up any audio that arrived during transcription
+                    drain!(ws_receiver, ws_sender, session, finalized, pending);
+
+                    // If EOS arrived during transcription, skip the
+                    // suggestion pass — loop back for a finalized transcription
+                    if finalized {
+                        continue;
+                    }
+
+                    // off the executor like the main transcription
+                    let (returned, retranscribed) =
+                        off_executor(session, move |session| {
+                            session.transcribe_from(
+                                retranscribe_from_cs,
+                                finalized,
+                            )
+                        })
+                        .await?;
+                    session = returned;
+                    match retranscribed {
+                        Ok(retranscribed_segments) => {
+                            let (exact_match, n_matching_tokens) =
+                                compare_segments(last, &retranscribed_segments);
+                            let suggestion = ServerMessage::AdvanceSuggestion {
+                                advance_cs: tx_advance_cs,
+                                timestamp_cs: last.end_cs,
+                                segments: retranscribed_segments,
+                                original_last_segment: last.clone(),
+                                exact_match,
+                                n_matching_tokens,
+                            };
+                            send!(ws_sender, outputs, compress, suggestion);
+                        }
+                        Err(e) => {
+                            error!("two-stroke retranscription error: {}", e);
                         }
                     }
                 }
             }
-            Ok(None) => {} // not enough audio
-            Err(e) => bail!(ws_sender, "Transcription error: {}", e),
+            Ok(None) => {}                   // not enough audio
+            Err(_) if preempted => continue, // retranscribe the fresher buffer
+            Err(_) if peer_gone => {
+                info!("Session ended, client left during the transcription");
+                return Ok(());
+            }
+            Err(e) => bail!(
+                ws_sender,
+                "TRANSCRIPTION_ERROR",
+                "Transcription error: {}",
+                e
+            ),
         }
+        drop(reservation); // before waiting for the client
 
         if finalized {
             break;
         }
 
-        ws_receiver.as_mut().peek().await; // block without consuming
+        if pending.is_empty() {
+            // block without consuming
+            tokio::select! {
+                _ = ws_receiver.as_mut().peek() => {}
+                _ = shutdown.changed() => {}
+                _ = idle_expired(server.idle_timeout, last_input) => bail!(
+                    ws_sender,
+                    "IDLE_TIMEOUT",
+                    "no input for {}s",
+                    last_input.elapsed().as_secs()
+                ),
+            }
+        }
     }
 
     ws_sender.send(Message::Close(None)).await?;
     info!("Session ended");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn transcripts_get_a_json_line_per_message() {
+        let path = std::env::temp_dir()
+            .join(format!("transcript-{}.jsonl", uuid::Uuid::new_v4()));
+        let (outputs, rx) = broadcast::channel(OUTPUTS_CAPACITY);
+        let writer = tokio::spawn(persist_transcript(rx, path.clone()));
+        outputs.send(ServerMessage::Pong { nonce: 1 }).unwrap();
+        outputs.send(ServerMessage::Pong { nonce: 2 }).unwrap();
+        drop(outputs); // the session ended
+        writer.await.unwrap().unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let nonces: Vec<u64> = written
+            .lines()
+            .map(|line| match serde_json::from_str(line).unwrap() {
+                ServerMessage::Pong { nonce } => nonce,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(nonces, [1, 2]);
+    }
+
+    #[test]
+    fn models_are_named_by_file_name() {
+        assert_eq!(
+            model_name("/srv/models/ggml-base.en.bin"),
+            "ggml-base.en.bin"
+        );
+        assert_eq!(model_name("ggml-tiny.bin"), "ggml-tiny.bin");
+    }
+
+    #[test]
+    fn reload_defaults_to_the_default_model() {
+        let reload = r#"{"type": "ReloadModel", "token": "secret"}"#;
+        let Ok(ClientMessage::ReloadModel { model, .. }) =
+            serde_json::from_str(reload)
+        else {
+            panic!("not a ReloadModel");
+        };
+        assert_eq!(model, None);
+    }
+
+    #[tokio::test]
+    async fn memory_budget_holds_reservations_until_released() {
+        let budget = MemoryBudget::new(1); // 1024 KiB
+        let first = budget.reserve(600 * 1024).await.unwrap();
+        let second = budget.reserve(600 * 1024);
+        let waited = Duration::from_millis(50);
+        assert!(tokio::time::timeout(waited, second).await.is_err());
+        drop(first);
+        let second = budget.reserve(600 * 1024).await.unwrap();
+        drop(second);
+        // larger than the whole budget, waits for exclusive use
+        let huge = budget.reserve(64 << 20).await.unwrap();
+        assert_eq!(budget.semaphore.available_permits(), 0);
+        drop(huge);
+        assert_eq!(budget.semaphore.available_permits(), 1024);
+    }
+
+    #[test]
+    fn unknown_config_fields_are_listed() {
+        let text = r#"{"type": "Configure", "language": "en", "langauge": "de",
+            "two_stroke": true, "beam": 5}"#;
+        assert_eq!(unknown_config_fields(text), ["beam", "langauge"]);
+        let text = r#"{"type": "Configure", "final_summary": true}"#;
+        assert!(unknown_config_fields(text).is_empty());
+    }
+
+    #[test]
+    fn messages_queued_during_whisper_are_drained_first() {
+        let ping = Message::Ping(Vec::new());
+        let newer = futures_util::stream::iter([Ok::<_, ()>(ping)]);
+        futures_util::pin_mut!(newer);
+        let mut pending = VecDeque::from([Message::binary(vec![1u8])]);
+        let first = next_ready(&mut pending, newer.as_mut());
+        assert!(matches!(first, Some(Some(Ok(Message::Binary(_))))));
+        let second = next_ready(&mut pending, newer.as_mut());
+        assert!(matches!(second, Some(Some(Ok(Message::Ping(_))))));
+        assert!(matches!(next_ready(&mut pending, newer), Some(None)));
+    }
+
+    #[tokio::test]
+    async fn configure_waits_only_as_long_as_the_timeout() {
+        let short = Some(Duration::from_millis(10));
+        let never = std::future::pending::<()>();
+        assert!(within(short, never).await.is_err());
+        assert_eq!(within(short, async { 1 }).await, Ok(1));
+        assert_eq!(within(None, async { 2 }).await, Ok(2));
+    }
+
+    #[test]
+    fn only_audio_and_advances_end_with_the_input() {
+        let text = |json: &str| Message::Text(json.to_string());
+        let advance = text(r#"{"type": "Advance", "timestamp_cs": 100}"#);
+        assert_eq!(late_input(&advance), Some("Advance"));
+        assert_eq!(late_input(&Message::binary(vec![0u8; 4])), Some("audio"));
+        assert_eq!(late_input(&text(r#"{"type": "GetState"}"#)), None);
+        assert_eq!(late_input(&text(r#"{"type": "Ping", "nonce": 1}"#)), None);
+    }
+
+    #[test]
+    fn messages_are_compressed_only_when_agreed() {
+        let pong = ServerMessage::Pong { nonce: 7 };
+        let json = serde_json::to_string(&pong).unwrap();
+        match encode(&pong, false).unwrap() {
+            Message::Text(text) => assert_eq!(text, json),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+        match encode(&pong, true).unwrap() {
+            Message::Binary(frame) => {
+                assert_eq!(frame[0], ZSTD_FRAME_TAG);
+                let decoded = zstd::decode_all(&frame[1..]).unwrap();
+                assert_eq!(decoded, json.as_bytes());
+            }
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn other_sessions_progress_during_a_decode() {
+        // the "decode" only finishes once the other session has sent audio,
+        // which it couldn't if the decode held up the single worker
+        let (audio_tx, audio_rx) = std::sync::mpsc::channel::<Vec<i16>>();
+        let decode = off_executor(audio_rx, |rx| rx.recv().unwrap().len());
+        let other = tokio::spawn(async move {
+            tokio::task::yield_now().await;
+            audio_tx.send(vec![0; 320]).unwrap();
+        });
+        let (_, decoded) = within(Some(Duration::from_secs(5)), decode)
+            .await
+            .expect("decode stuck")
+            .unwrap();
+        assert_eq!(decoded, 320);
+        other.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaving_aborts_the_decode() {
+        // a decode that would take 10s unless aborted
+        let abort = Arc::new(AtomicBool::new(false));
+        let flag = abort.clone();
+        let mut decode = off_executor((), move |_| {
+            let start = Instant::now();
+            while !flag.load(Ordering::Relaxed) {
+                if start.elapsed() > Duration::from_secs(10) {
+                    return false;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            true
+        });
+        // the client sends a Ping, then disconnects
+        let ping = Message::Text(r#"{"type":"Ping","nonce":1}"#.into());
+        let receiver = futures_util::stream::iter([Ok::<_, ()>(ping)]);
+        futures_util::pin_mut!(receiver);
+
+        let mut pending = VecDeque::new();
+        let mut watch = DecodeWatch {
+            abort: &abort,
+            pending: &mut pending,
+            preemptible: false,
+            preempted: false,
+            peer_gone: false,
+        };
+        let no_preview: Option<&mut JoinHandle<()>> = None;
+        let watched = watch.until_done(&mut decode, no_preview, receiver);
+        let Watched::Decoded(joined) =
+            within(Some(Duration::from_secs(5)), watched)
+                .await
+                .expect("decode not aborted")
+        else {
+            panic!("no preview was running");
+        };
+        assert!(joined.unwrap().1, "decode ran to the end");
+        assert!(watch.peer_gone && !watch.preempted);
+        assert!(abort.load(Ordering::Relaxed));
+        assert_eq!(pending.len(), 2); // the Ping, then the Close
+        assert!(matches!(pending[1], Message::Close(None)));
+    }
+}
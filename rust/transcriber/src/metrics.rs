@@ -0,0 +1,126 @@
+use anyhow::Result;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+/// Upper bounds of the realtime factor histogram buckets, +Inf is implied.
+const RTF_BUCKETS: [f64; 7] = [0.5, 1., 2., 4., 8., 16., 32.];
+
+/// Server-wide counters, always collected, exposed with --metrics-port.
+pub struct Metrics {
+    active_sessions: AtomicU64,
+    connections: AtomicU64,
+    transcriptions: AtomicU64,
+    rtf_buckets: [AtomicU64; RTF_BUCKETS.len()], // not cumulative
+    rtf_sum: AtomicU64,                          // f64 bits
+}
+
+pub static METRICS: Metrics = Metrics {
+    active_sessions: AtomicU64::new(0),
+    connections: AtomicU64::new(0),
+    transcriptions: AtomicU64::new(0),
+    rtf_buckets: [const { AtomicU64::new(0) }; RTF_BUCKETS.len()],
+    rtf_sum: AtomicU64::new(0),
+};
+
+/// Counts a session as active until dropped.
+pub struct ActiveSession;
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        METRICS.active_sessions.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) -> ActiveSession {
+        self.connections.fetch_add(1, Relaxed);
+        self.active_sessions.fetch_add(1, Relaxed);
+        ActiveSession
+    }
+
+    pub fn transcribed(&self, realtime_factor: f64) {
+        self.transcriptions.fetch_add(1, Relaxed);
+        if let Some(i) = RTF_BUCKETS.iter().position(|&b| realtime_factor <= b)
+        {
+            self.rtf_buckets[i].fetch_add(1, Relaxed);
+        }
+        let _ = self.rtf_sum.fetch_update(Relaxed, Relaxed, |bits| {
+            Some((f64::from_bits(bits) + realtime_factor).to_bits())
+        });
+    }
+
+    /// The Prometheus text exposition format.
+    fn render(&self) -> String {
+        let transcriptions = self.transcriptions.load(Relaxed);
+        let mut out = format!(
+            "# TYPE whisper_active_sessions gauge\n\
+             whisper_active_sessions {}\n\
+             # TYPE whisper_connections_total counter\n\
+             whisper_connections_total {}\n\
+             # TYPE whisper_transcriptions_total counter\n\
+             whisper_transcriptions_total {}\n\
+             # TYPE whisper_realtime_factor histogram\n",
+            self.active_sessions.load(Relaxed),
+            self.connections.load(Relaxed),
+            transcriptions,
+        );
+        let mut cumulative = 0;
+        for (bound, count) in RTF_BUCKETS.iter().zip(&self.rtf_buckets) {
+            cumulative += count.load(Relaxed);
+            let _ = writeln!(
+                out,
+                "whisper_realtime_factor_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let sum = f64::from_bits(self.rtf_sum.load(Relaxed));
+        let _ = writeln!(
+            out,
+            "whisper_realtime_factor_bucket{{le=\"+Inf\"}} {}\n\
+             whisper_realtime_factor_sum {}\n\
+             whisper_realtime_factor_count {}",
+            transcriptions, sum, transcriptions
+        );
+        out
+    }
+}
+
+/// Serves GET /metrics over plain HTTP, independently of the WebSockets.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Serving metrics on {}", addr);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                error!("metrics request error: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let mut request = [0u8; 1024]; // the request line is all we look at
+    let n = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..n]);
+    let response = if request.starts_with("GET /metrics ") {
+        let body = METRICS.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+         Connection: close\r\n\r\n"
+            .to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
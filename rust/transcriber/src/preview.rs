@@ -0,0 +1,213 @@
+use crate::session::{
+    avg_logprob, hallucinated, hinted_speaker, segment_text,
+    vad_whisper_disagreement,
+};
+use anyhow::Result;
+use shared_protocol::{
+    SAMPLE_RATE, Segment, ServerMessage, format_timecode, majority_speaker,
+    tokens_to_words,
+};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
+
+const FAST_PREVIEW_AUDIO_CTX: i32 = 192; // enough for 3.84s
+
+/// The tail of a session's buffer to be decoded into a fast_preview,
+/// together with what the segment needs from the session, so that it can
+/// be decoded while the session itself is busy; see `Session::preview_job`.
+pub struct PreviewJob {
+    pub audio: Vec<f32>,
+    pub start_cs: i64,
+    pub end_cs: i64,
+    pub advance_cs: i64,
+    pub language: Option<String>, // None = auto-detect
+    pub translate: bool,
+    pub hallucination_repeats: usize, // see session::hallucinated
+    pub hallucination_no_speech: f32,
+    pub min_samples: usize, // shorter tails are not decoded
+    pub end_vad_probability: f32,
+    pub speaker_hints: Vec<(i64, i64, i32)>, // those reaching into the tail
+    pub disagreement_margin: f32,
+    pub formatted_timecodes: bool,
+    pub best_of: i32, // of the greedy decode, see --preview-best-of
+}
+
+impl PreviewJob {
+    /// A Transcription carrying only a fast_preview, greedily decoded into
+    /// a single segment with a tiny audio_ctx. None if the tail is too
+    /// short.
+    pub fn decode(
+        &self,
+        ctx: &WhisperContext,
+        state: &mut WhisperState,
+    ) -> Result<Option<ServerMessage>> {
+        if self.audio.len() < self.min_samples {
+            return Ok(None);
+        }
+        let mut params = FullParams::new(self.sampling_strategy());
+        params.set_language(self.language.as_deref());
+        params.set_translate(self.translate);
+        params.set_suppress_nst(true);
+        params.set_single_segment(true);
+        params.set_print_progress(false);
+        params.set_print_special(false);
+        params.set_print_realtime(false);
+        params.set_token_timestamps(true);
+        params.set_no_context(true);
+        params.set_audio_ctx(FAST_PREVIEW_AUDIO_CTX);
+
+        let start = Instant::now();
+        state.full(params, &self.audio)?;
+        info!(
+            "fast preview of {:.2}s took {:.2}s",
+            self.audio.len() as f64 / SAMPLE_RATE as f64,
+            start.elapsed().as_secs_f64()
+        );
+
+        let Some(segment) = state.get_segment(0) else {
+            return Ok(None);
+        };
+        let eot_id = ctx.token_eot();
+        let mut tokens = Vec::new();
+        let mut text_bytes = Vec::new();
+        let mut logprobs = Vec::new();
+        for j in 0..segment.n_tokens() {
+            if let Some(token) = segment.get_token(j) {
+                if token.token_id() >= eot_id {
+                    continue; // text only, no prompt context is built from it
+                }
+                text_bytes.extend_from_slice(token.to_bytes()?);
+                let token_data = token.token_data();
+                logprobs.push(token_data.plog);
+                let start_cs = token_data.t0 + self.start_cs;
+                let end_cs = token_data.t1 + self.start_cs;
+                tokens.push(shared_protocol::Token {
+                    text: token.to_str_lossy()?.to_string(),
+                    id: token.token_id(),
+                    special: false,
+                    start_cs,
+                    end_cs,
+                    probability: token.token_probability(),
+                    speaker_id: hinted_speaker(
+                        &self.speaker_hints,
+                        start_cs,
+                        end_cs,
+                    ),
+                });
+            }
+        }
+        let no_speech_probability = segment.no_speech_probability();
+        let timecode =
+            |cs| self.formatted_timecodes.then(|| format_timecode(cs));
+        let preview = Segment {
+            text: segment_text(&text_bytes),
+            start_cs: self.start_cs,
+            end_cs: self.end_cs,
+            words: tokens_to_words(&tokens),
+            speaker_id: majority_speaker(&tokens),
+            hallucinated: hallucinated(
+                &tokens,
+                no_speech_probability,
+                self.end_vad_probability,
+                self.hallucination_repeats,
+                self.hallucination_no_speech,
+            ),
+            tokens,
+            fallback_segmentation: true, // spans the tail, not the speech
+            end_vad_probability: self.end_vad_probability,
+            no_speech_probability,
+            avg_logprob: avg_logprob(&logprobs),
+            is_final: false,
+            vad_whisper_disagreement: vad_whisper_disagreement(
+                no_speech_probability,
+                self.end_vad_probability,
+                self.disagreement_margin,
+            ),
+            timestamp_unreliable: false,
+            start_tc: timecode(self.start_cs),
+            end_tc: timecode(self.end_cs),
+            seq: None,
+        };
+        Ok(Some(ServerMessage::Transcription {
+            complete: Vec::new(),
+            incomplete: None,
+            fast_preview: Some(preview),
+            advance_cs: self.advance_cs,
+        }))
+    }
+
+    // greedy whatever the session's strategy, previews have to be quick
+    fn sampling_strategy(&self) -> SamplingStrategy {
+        SamplingStrategy::Greedy {
+            best_of: self.best_of.max(1),
+        }
+    }
+}
+
+/// A second, smaller model decoding fast previews with a state of its own,
+/// alongside the session's full transcriptions; see --fast-preview-model.
+pub struct PreviewModel {
+    ctx: Arc<WhisperContext>,
+    state: WhisperState,
+}
+
+impl PreviewModel {
+    pub fn new(ctx: Arc<WhisperContext>) -> Result<Self> {
+        let state = ctx.create_state()?;
+        Ok(Self { ctx, state })
+    }
+
+    pub fn run(&mut self, job: &PreviewJob) -> Result<Option<ServerMessage>> {
+        job.decode(&self.ctx, &mut self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(best_of: i32) -> PreviewJob {
+        PreviewJob {
+            audio: Vec::new(),
+            start_cs: 0,
+            end_cs: 0,
+            advance_cs: 0,
+            language: None,
+            translate: false,
+            hallucination_repeats: 0,
+            hallucination_no_speech: 0.,
+            min_samples: 0,
+            end_vad_probability: 0.,
+            speaker_hints: Vec::new(),
+            disagreement_margin: 0.,
+            formatted_timecodes: false,
+            best_of,
+        }
+    }
+
+    #[test]
+    fn previews_decode_greedily_with_their_own_best_of() {
+        assert!(matches!(
+            job(3).sampling_strategy(),
+            SamplingStrategy::Greedy { best_of: 3 }
+        ));
+        assert!(matches!(
+            job(0).sampling_strategy(),
+            SamplingStrategy::Greedy { best_of: 1 }
+        ));
+    }
+
+    #[test]
+    fn previews_decode_characters_split_across_tokens() {
+        // byte-fallback tokens as whisper emits them for rare kanji, each
+        // one invalid UTF-8 alone
+        let text = " 鰯の頭も信心から";
+        let tokens: Vec<&[u8]> = text.as_bytes().chunks(2).collect();
+        let per_token: String =
+            tokens.iter().map(|t| String::from_utf8_lossy(t)).collect();
+        assert!(per_token.contains(char::REPLACEMENT_CHARACTER));
+        assert_eq!(segment_text(&tokens.concat()), "鰯の頭も信心から");
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per-IP token bucket limiting the rate of new connections.
+pub struct RateLimiter {
+    rate_per_s: f64, // tokens refilled per second
+    burst: f64,      // bucket capacity
+    buckets: HashMap<IpAddr, Bucket>,
+    last_cleanup: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            rate_per_s: per_minute as f64 / 60.,
+            burst: burst.max(1) as f64,
+            buckets: HashMap::new(),
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Returns whether a new connection from `ip` is allowed right now.
+    pub fn check(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_cleanup) >= CLEANUP_INTERVAL {
+            self.cleanup(now);
+        }
+
+        let burst = self.burst;
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate_per_s).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+
+    // forget IPs whose buckets would have refilled completely by now
+    fn cleanup(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate_per_s, self.burst);
+        self.buckets.retain(|_, b| {
+            let elapsed = now.duration_since(b.last_refill).as_secs_f64();
+            b.tokens + elapsed * rate < burst
+        });
+        self.last_cleanup = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn rapid_connections_from_one_ip_exhaust_the_burst() {
+        let mut limiter = RateLimiter::new(60, 3);
+        let allowed = (0..10).filter(|_| limiter.check(IP)).count();
+        assert_eq!(allowed, 3);
+        // other clients have buckets of their own
+        assert!(limiter.check(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_configured_rate() {
+        let mut limiter = RateLimiter::new(60, 3);
+        while limiter.check(IP) {}
+        let bucket = limiter.buckets.get_mut(&IP).unwrap();
+        bucket.last_refill -= Duration::from_secs(2); // 1 token per second
+        assert!(limiter.check(IP));
+        assert!(limiter.check(IP));
+        assert!(!limiter.check(IP));
+    }
+
+    #[test]
+    fn cleanup_forgets_refilled_buckets() {
+        let mut limiter = RateLimiter::new(60, 3);
+        assert!(limiter.check(IP));
+        let later = Instant::now() + Duration::from_secs(2);
+        limiter.cleanup(later);
+        assert!(limiter.buckets.is_empty());
+    }
+}
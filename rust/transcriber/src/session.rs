@@ -1,33 +1,76 @@
+use crate::gain::AutoGain;
+use crate::granularity::Granularity;
+use crate::longform;
+use crate::metrics::METRICS;
+use crate::preview::PreviewJob;
+use crate::wav::WavWriter;
 use anyhow::Result;
 use opus::{Channels, Decoder};
 use shared_protocol::{
-    CS_SAMPLES, FRAME_SIZE_SAMPLES, SAMPLE_RATE, ServerMessage,
+    CS_SAMPLES, Config, FRAME_SIZE_SAMPLES, LOW_CONFIDENCE, PROTOCOL_VERSION,
+    SAMPLE_RATE, SEQ_MATCH_CS, Segment, ServerMessage, Token,
+    UNCERTAINTY_TOKENS, Word, format_timecode, majority_speaker,
+    timestamp_unreliable, tokens_to_words,
 };
 use shared_vad::Vad;
-use std::ffi::c_int;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ffi::{c_int, c_void};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
-use tracing::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info};
 use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
 
 const MAX_PROMPT_TOKENS: usize = 224; // half of whisper's 448-token context
-const MIN_FRAMES: u32 = 3; // do not transcribe if shorter than 3*60 = 180 ms
-const MIN_SAMPLES: usize = (MIN_FRAMES * FRAME_SIZE_SAMPLES) as usize;
+const MAX_LOOP_NGRAM: usize = 4; // longest token n-gram checked for loops
+const SPEECH_ENTER: f32 = 0.6; // VAD probability at which speech starts
+const SPEECH_EXIT: f32 = 0.3; // and below which it ends
+const FINAL_MAX_NO_SPEECH: f32 = 0.6; // see drop_low_quality_final
+const DEFAULT_DISAGREEMENT_MARGIN: f32 = 0.5;
+const MAX_TEMPERATURE: f32 = 1.0;
+const RATE_BURST_S: f64 = 10.; // audio allowed ahead of max_audio_rate
+const FULL_AUDIO_CTX: i32 = 1500; // whisper's audio_ctx for a 30s window
+const DTX_HEADER_LEN: usize = 4; // u32 LE frame index before each packet
+const MAX_DTX_GAP_FRAMES: u32 = 1000; // 60s of silence at most
+const MAX_OPUS_FRAME_SAMPLES: usize = 1920; // 120ms, the longest Opus packet
+const FAST_PREVIEW_CS: i64 = 300; // tail covered by the fast preview
+const DEFAULT_AUTO_GAIN_SMOOTHING: f32 = 0.05;
+const AGC_TARGET_DBFS: f32 = -20.; // for auto-gain enabled with --agc
+const QUALITY_WINDOW: usize = 3; // transcriptions that must agree to switch
+const DEGRADE_BELOW_RTF: f64 = 1.0; // slower than realtime, falling behind
+const RESTORE_ABOVE_RTF: f64 = 2.5; // caught up even with the full quality
 
 #[derive(Clone, Debug)]
 pub struct TranscribeOpts {
     pub dynamic_audio_ctx: bool,
     pub temperature_inc: Option<f32>,
     pub entropy_thold: Option<f32>,
+    pub logprob_thold: Option<f32>,
+    pub no_speech_thold: f32, // segments above it are not sent
+    pub min_token_prob: f32,  // see trim_unlikely_tail
     pub reinit_state: bool,
+    pub agc: bool, // auto-gain sessions not setting auto_gain_dbfs
+    pub max_audio_rate: Option<f64>, // max audio seconds per wall-clock second
+    pub min_audio_cs: i64, // do not transcribe anything shorter, positive
+    pub max_window_cs: i64, // longer buffers are advanced, see auto_advance
+    pub hallucination_repeats: usize, // see hallucinated, 0 = off
+    pub hallucination_no_speech: f32,
+    pub dump_audio_dir: Option<PathBuf>, // for WAV files of the sessions
+    pub preview_best_of: i32,            // of the greedy fast_preview decode
 }
 
 pub struct Session {
     ctx: Arc<WhisperContext>,
-    language: Option<String>, // None = auto-detect
-    context: Option<String>,
+    language: Option<String>,   // None = auto-detect
+    context_tokens: Vec<c_int>, // tokenized context, prepended to the prompt
     opus_decoder: Decoder,
-    accumulated_audio: Vec<i16>,
+    audio_f32_scratch: Vec<f32>, // whisper input, kept for its allocation
+    // starts at advance_cs, a ring buffer so that advancing only touches
+    // the dropped samples
+    accumulated_audio: VecDeque<i16>,
     whisper_state: WhisperState, // reuse state for performance
     vad: Vad,
     prompt_tokens: Vec<c_int>, // token IDs from last transcription, for context
@@ -40,62 +83,879 @@ pub struct Session {
     max_tokens: i32,
     single_segment: bool,
     max_initial_ts: f32,
+    last_audio_ctx: i32, // audio_ctx passed to whisper on the last call
+    next_frame_index: Option<u32>, // expected frame index, only with DTX
+    last_frame_samples: usize, // of the last Opus frame, DTX gaps are alike
+    lost_frames: u32,    // reported lost before the next frame, see PacketLost
+    concealed_frames: u64, // total, for the logs
+    audio_dump: Option<WavWriter>, // the audio as transcribed, for debugging
+    words_emitted_up_to_cs: i64, // end of the last word in WordsAppended
+    min_speech: bool,    // minimum length counts VAD speech, not buffer length
+    drop_low_quality_final: bool,
+    echo: Option<Vec<u8>>, // PCM to send back to the client, if enabled
+    disagreement_margin: f32,
+    audio_started: Option<Instant>, // when the first audio frame arrived
+    received_samples: u64,          // total samples appended, for rate limits
+    temperature: Option<f32>,       // None = whisper default (0)
+    formatted_timecodes: bool,
+    abort: Arc<AtomicBool>, // aborts the running transcribe when set
+    auto_gain: Option<AutoGain>,
+    adaptive_quality: bool,
+    degraded: bool, // adaptive quality currently lowered
+    recent_rtf: VecDeque<f64>, // realtime factors, newest last
+    quality_changed: Option<ServerMessage>, // not yet sent to the client
+    seqs: Option<SeqNumbers>, // with segment_seq
+    confirmed: Option<Vec<Segment>>, // Advance-confirmed, with session_export
+    summary: Option<SummaryTotals>, // Advance-confirmed, with final_summary
+    last_complete: Vec<Segment>, // of the last Transcription, see advance
+    window_boundary: Option<Segment>, // to auto-advance to, see auto_advance
+    logit_bias: Vec<(i32, f32)>, // (token ID, bias), see apply_logit_bias
+    granularity: Option<Granularity>,
+    split_on_word: bool,
+    fast_preview: bool,
+    trim_leading_silence: bool,
+    speaker_hints: Vec<(i64, i64, i32)>, // (start_cs, end_cs, speaker_id)
+    paused: bool,    // drop incoming audio, see ClientMessage::Pause
+    pcm: bool,       // binary frames are s16le PCM, not Opus
+    translate: bool, // into English, see Config::task
+    odd_byte: Option<u8>, // first half of a sample split across PCM frames
 }
 
 impl Session {
     pub fn new(
         ctx: Arc<WhisperContext>,
-        language: Option<String>,
-        context: Option<String>,
-        max_len: Option<i32>,
-        max_tokens: Option<i32>,
-        single_segment: Option<bool>,
-        max_initial_ts: Option<f32>,
+        whisper_state: Option<WhisperState>, // a warm one, if available
+        config: &Config,
         sampling_strategy: SamplingStrategy,
         opts: TranscribeOpts,
     ) -> Result<Self> {
         let opus_decoder = Decoder::new(SAMPLE_RATE, Channels::Mono)?;
-        let whisper_state = ctx.create_state()?;
+        let whisper_state = match whisper_state {
+            Some(state) => state,
+            None => ctx.create_state()?,
+        };
+
+        let context_tokens = match config.context.as_deref().map(str::trim) {
+            Some(c) if !c.is_empty() => ctx.tokenize(c, c.len() + 1)?,
+            _ => Vec::new(),
+        };
+
+        let logit_bias = config.logit_bias.clone().unwrap_or_default();
+        check_logit_bias(&logit_bias, ctx.n_vocab())?;
+
+        let granularity = match config.granularity.as_deref() {
+            Some(g) => match Granularity::parse(g) {
+                Some(g) => Some(g),
+                None => anyhow::bail!("unknown granularity {:?}", g),
+            },
+            None => None,
+        };
+
+        let pcm = match config.audio_format.as_deref() {
+            None | Some("opus") => false,
+            Some("pcm_s16le") => true,
+            Some(f) => anyhow::bail!("unknown audio_format {:?}", f),
+        };
+        if pcm && config.dtx.unwrap_or(false) {
+            anyhow::bail!("dtx is only supported with Opus");
+        }
+
+        let translate = match config.task.as_deref() {
+            None | Some("transcribe") => false,
+            Some("translate") if !ctx.is_multilingual() => {
+                anyhow::bail!("an English-only model cannot translate")
+            }
+            Some("translate") => true,
+            Some(t) => anyhow::bail!("unknown task {:?}", t),
+        };
+
+        let mut vad = Vad::new();
+        vad.set_hysteresis(SPEECH_ENTER, SPEECH_EXIT);
 
-        let language_opt = language.filter(|l| !l.is_empty() && l != "auto");
+        let language_opt = config
+            .language
+            .clone()
+            .filter(|l| !l.is_empty() && l != "auto");
         match &language_opt {
             Some(lang) => info!("Session created with language {}", lang),
             None => info!("Session created with language auto-detection"),
         }
 
+        let auto_gain = config
+            .auto_gain_dbfs
+            .or(opts.agc.then_some(AGC_TARGET_DBFS))
+            .map(|target| {
+                let smoothing = config
+                    .auto_gain_smoothing
+                    .unwrap_or(DEFAULT_AUTO_GAIN_SMOOTHING);
+                AutoGain::new(target.min(0.), smoothing)
+            });
+        let audio_dump = opts.dump_audio_dir.as_deref().and_then(create_dump);
+
         Ok(Self {
             ctx,
             language: language_opt,
-            context,
+            context_tokens,
             opus_decoder,
-            accumulated_audio: Vec::new(),
+            accumulated_audio: VecDeque::new(),
+            audio_f32_scratch: Vec::new(),
             whisper_state,
-            vad: Vad::new(),
+            vad,
             prompt_tokens: Vec::new(),
             advance_cs: 0,
             transcribed_up_to_cs: 0,
             advanced_since: false,
             sampling_strategy,
             opts,
-            max_len: max_len.unwrap_or(0),
-            max_tokens: max_tokens.unwrap_or(0),
-            single_segment: single_segment.unwrap_or(false),
-            max_initial_ts: max_initial_ts.unwrap_or(0.),
+            max_len: config
+                .max_len
+                .or(granularity.map(Granularity::max_len))
+                .unwrap_or(0),
+            max_tokens: config.max_tokens.unwrap_or(0),
+            single_segment: config
+                .single_segment
+                .or(granularity.map(Granularity::single_segment))
+                .unwrap_or(false),
+            max_initial_ts: config.max_initial_ts.unwrap_or(0.),
+            last_audio_ctx: FULL_AUDIO_CTX,
+            next_frame_index: config.dtx.unwrap_or(false).then_some(0),
+            last_frame_samples: FRAME_SIZE_SAMPLES as usize,
+            lost_frames: 0,
+            concealed_frames: 0,
+            audio_dump,
+            words_emitted_up_to_cs: 0,
+            min_speech: config.min_speech.unwrap_or(false),
+            drop_low_quality_final: config
+                .drop_low_quality_final
+                .unwrap_or(false),
+            echo: config.audio_echo.unwrap_or(false).then(Vec::new),
+            disagreement_margin: config
+                .disagreement_margin
+                .unwrap_or(DEFAULT_DISAGREEMENT_MARGIN),
+            audio_started: None,
+            received_samples: 0,
+            temperature: initial_temperature(config),
+            formatted_timecodes: config.formatted_timecodes.unwrap_or(false),
+            abort: Arc::new(AtomicBool::new(false)),
+            auto_gain,
+            adaptive_quality: config.adaptive_quality.unwrap_or(false),
+            degraded: false,
+            recent_rtf: VecDeque::with_capacity(QUALITY_WINDOW),
+            quality_changed: None,
+            seqs: config
+                .segment_seq
+                .unwrap_or(false)
+                .then(SeqNumbers::default),
+            confirmed: config.session_export.unwrap_or(false).then(Vec::new),
+            summary: config
+                .final_summary
+                .unwrap_or(false)
+                .then(SummaryTotals::default),
+            last_complete: Vec::new(),
+            window_boundary: None,
+            logit_bias,
+            granularity,
+            split_on_word: granularity.is_some_and(Granularity::split_on_word),
+            fast_preview: config.fast_preview.unwrap_or(false),
+            trim_leading_silence: config.trim_leading_silence.unwrap_or(false),
+            speaker_hints: Vec::new(),
+            paused: false,
+            pcm,
+            translate,
+            odd_byte: None,
         })
     }
 
-    pub fn decode_and_append_opus(&mut self, packet: &[u8]) -> Result<()> {
-        let mut output = vec![0i16; FRAME_SIZE_SAMPLES as usize];
+    /// The configuration as the session interprets it, with all defaults
+    /// filled in and the token left out.
+    pub fn applied_config(&self, config: &Config) -> Config {
+        Config {
+            token: None,
+            protocol_version: Some(
+                config.protocol_version.unwrap_or(PROTOCOL_VERSION),
+            ),
+            language: Some(self.language.clone().unwrap_or("auto".into())),
+            context: config.context.clone(),
+            max_len: Some(self.max_len),
+            max_tokens: Some(self.max_tokens),
+            single_segment: Some(self.single_segment),
+            max_initial_ts: Some(self.max_initial_ts),
+            no_preview: Some(config.no_preview.unwrap_or(false)),
+            two_stroke: Some(config.two_stroke.unwrap_or(false)),
+            stats: Some(config.stats.unwrap_or(false)),
+            dtx: Some(self.next_frame_index.is_some()),
+            interim_words: Some(config.interim_words.unwrap_or(false)),
+            language_probabilities: config.language_probabilities,
+            long_form: Some(config.long_form.unwrap_or(false)),
+            final_summary: Some(self.summary.is_some()),
+            min_speech: Some(self.min_speech),
+            drop_low_quality_final: Some(self.drop_low_quality_final),
+            audio_echo: Some(self.echo.is_some()),
+            disagreement_margin: Some(self.disagreement_margin),
+            temperature: Some(self.temperature.unwrap_or(0.)),
+            config_ack: Some(config.config_ack.unwrap_or(true)),
+            formatted_timecodes: Some(self.formatted_timecodes),
+            auto_gain_dbfs: self.auto_gain.as_ref().map(AutoGain::target_dbfs),
+            auto_gain_smoothing: self.auto_gain.as_ref().map(|_| {
+                config
+                    .auto_gain_smoothing
+                    .unwrap_or(DEFAULT_AUTO_GAIN_SMOOTHING)
+                    .clamp(0.001, 1.)
+            }),
+            adaptive_quality: Some(self.adaptive_quality),
+            segment_seq: Some(self.seqs.is_some()),
+            session_export: Some(self.confirmed.is_some()),
+            uncertainty: Some(config.uncertainty.unwrap_or(false)),
+            logit_bias: Some(self.logit_bias.clone()),
+            granularity: self.granularity.map(|g| g.name().to_string()),
+            fast_preview: Some(self.fast_preview),
+            trim_leading_silence: Some(self.trim_leading_silence),
+            compression: config.compression.clone(),
+            audio_format: Some(
+                if self.pcm { "pcm_s16le" } else { "opus" }.to_string(),
+            ),
+            model: config.model.clone(),
+            task: Some(
+                if self.translate {
+                    "translate"
+                } else {
+                    "transcribe"
+                }
+                .to_string(),
+            ),
+        }
+    }
+
+    /// Switches language and/or context mid-stream. Invalid values are
+    /// rejected without changing anything.
+    pub fn reconfigure(
+        &mut self,
+        language: Option<String>,
+        context: Option<String>,
+    ) -> Result<()> {
+        let language = match language.as_deref() {
+            None => self.language.clone(),
+            Some("auto") => None,
+            Some(lang) if whisper_rs::get_lang_id(lang).is_some() => {
+                Some(lang.to_string())
+            }
+            Some(lang) => anyhow::bail!("unknown language {:?}", lang),
+        };
+        if let Some(context) = context {
+            self.context_tokens = match context.trim() {
+                "" => Vec::new(),
+                c => self.ctx.tokenize(c, c.len() + 1)?,
+            };
+        }
+        info!("Reconfigured language to {:?}", language);
+        self.language = language;
+        Ok(())
+    }
+
+    /// Adds diarization hints, they apply to transcriptions from now on,
+    /// including retranscriptions of audio already received.
+    pub fn add_speaker_hints(
+        &mut self,
+        ranges: Vec<(i64, i64, i32)>,
+    ) -> Result<()> {
+        if let Some(r) = ranges.iter().find(|(start, end, _)| end <= start) {
+            anyhow::bail!("empty speaker range {}..{}", r.0, r.1);
+        }
+        self.speaker_hints.extend(ranges);
+        Ok(())
+    }
+
+    fn speaker_at(&self, start_cs: i64, end_cs: i64) -> Option<i32> {
+        hinted_speaker(&self.speaker_hints, start_cs, end_cs)
+    }
+
+    /// Setting this flag aborts a `transcribe`, `transcribe_from` or
+    /// `transcribe_long_form` running in another thread,
+    /// the caller resets it before starting the next one.
+    pub fn abort_handle(&self) -> Arc<AtomicBool> {
+        self.abort.clone()
+    }
+
+    // The decode parameters of `transcribe` and `transcribe_range`, which
+    // set the few that differ on top. Uses the `last_audio_ctx` of the
+    // buffer to be decoded, and `self.logit_bias` unborrowed: the params
+    // have to be passed to full() before it changes.
+    fn full_params<'a, 'b>(
+        &self,
+        language: Option<&'a str>, // None = auto-detect
+        prompt: &'b [c_int],
+    ) -> FullParams<'a, 'b> {
+        let mut params = FullParams::new(self.sampling_strategy());
+        params.set_language(language);
+        params.set_translate(self.translate);
+        params.set_suppress_nst(true);
+        params.set_max_len(self.max_len);
+        params.set_split_on_word(self.split_on_word);
+        params.set_max_tokens(self.max_tokens);
+        params.set_print_progress(false);
+        params.set_print_special(false);
+        params.set_print_realtime(false);
+        params.set_token_timestamps(true); // token-level timing
+        params.set_tokens(prompt); // instead of set_initial_prompt
+        params.set_no_context(true);
+        let abort = self.abort.clone();
+        params.set_abort_callback_safe(move || abort.load(Ordering::Relaxed));
+        if !self.logit_bias.is_empty() {
+            // SAFETY: logit_bias outlives the full() call, see above
+            unsafe {
+                params.set_filter_logits_callback(Some(apply_logit_bias));
+                params.set_filter_logits_callback_user_data(
+                    &self.logit_bias as *const _ as *mut c_void,
+                );
+            }
+        }
+
+        if let Some(v) = self.temperature {
+            params.set_temperature(v); // retries add temperature_inc to it
+        }
+        if let Some(v) = self.opts.temperature_inc {
+            params.set_temperature_inc(v);
+        }
+        if let Some(v) = self.opts.entropy_thold {
+            params.set_entropy_thold(v);
+        }
+        if let Some(v) = self.opts.logprob_thold {
+            params.set_logprob_thold(v);
+        }
+        if self.opts.dynamic_audio_ctx || self.degraded {
+            params.set_audio_ctx(self.last_audio_ctx);
+        }
+        params
+    }
+
+    /// Effective audio_ctx for a buffer of `n_samples`.
+    fn audio_ctx_for(&self, n_samples: usize) -> i32 {
+        if !self.opts.dynamic_audio_ctx && !self.degraded {
+            return FULL_AUDIO_CTX;
+        }
+        scaled_audio_ctx(n_samples)
+    }
+
+    /// The configured sampling strategy, or greedy while degraded.
+    fn sampling_strategy(&self) -> SamplingStrategy {
+        if self.degraded {
+            SamplingStrategy::Greedy { best_of: 1 }
+        } else {
+            self.sampling_strategy.clone()
+        }
+    }
+
+    /// Switches adaptive quality down after several transcriptions slower
+    /// than realtime and back up once several are comfortably faster.
+    fn track_realtime_factor(&mut self, realtime_factor: f64) {
+        if !self.adaptive_quality {
+            return;
+        }
+        if self.recent_rtf.len() == QUALITY_WINDOW {
+            self.recent_rtf.pop_front();
+        }
+        self.recent_rtf.push_back(realtime_factor);
+        if quality_switch(&self.recent_rtf, self.degraded) {
+            self.degraded = !self.degraded;
+            self.recent_rtf.clear();
+            info!(
+                "adaptive quality {} at {:.2}x",
+                if self.degraded {
+                    "degraded"
+                } else {
+                    "restored"
+                },
+                realtime_factor
+            );
+            self.quality_changed = Some(ServerMessage::QualityChanged {
+                degraded: self.degraded,
+                realtime_factor,
+            });
+        }
+    }
+
+    pub fn take_quality_changed(&mut self) -> Option<ServerMessage> {
+        self.quality_changed.take()
+    }
+
+    pub fn stats(&self) -> ServerMessage {
+        ServerMessage::Stats {
+            advance_cs: self.advance_cs,
+            audio_ctx: self.last_audio_ctx,
+        }
+    }
+
+    /// Memory taken by the f32 copy of the buffer during transcription.
+    pub fn transcription_bytes(&self) -> usize {
+        self.accumulated_audio.len() * std::mem::size_of::<f32>()
+    }
+
+    pub fn state(&self) -> ServerMessage {
+        ServerMessage::AdvanceState {
+            advance_cs: self.advance_cs,
+            buffer_cs: (self.accumulated_audio.len() as i64 * 100)
+                / SAMPLE_RATE as i64,
+            transcribed_up_to_cs: self.transcribed_up_to_cs,
+        }
+    }
+
+    /// Top-K languages detected in the audio of the last transcription,
+    /// None if the language is fixed. Costs an extra encoder pass.
+    pub fn language_probabilities(
+        &mut self,
+        top_k: usize,
+    ) -> Result<Option<ServerMessage>> {
+        if self.language.is_some() {
+            return Ok(None);
+        }
+        let (_, probabilities) = self.whisper_state.lang_detect(0, 1)?;
+        let languages = probabilities
+            .iter()
+            .enumerate()
+            .filter_map(|(id, &p)| {
+                let lang = whisper_rs::get_lang_str(id as i32)?;
+                Some((lang.to_string(), p))
+            })
+            .collect();
+        let ranked = rank_languages(languages, top_k);
+        Ok(Some(ServerMessage::LanguageProbabilities { ranked }))
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn decode_and_append_opus(&mut self, data: &[u8]) -> Result<()> {
+        if self.paused {
+            // keep following DTX indices, so that the frames dropped
+            // while paused aren't filled in with silence on resume
+            if self.next_frame_index.is_some() && data.len() >= DTX_HEADER_LEN {
+                let header = data[..DTX_HEADER_LEN].try_into()?;
+                let index = u32::from_le_bytes(header);
+                self.next_frame_index = Some(next_dtx_index(index)?);
+            }
+            self.lost_frames = 0; // nothing to conceal between paused frames
+            return Ok(());
+        }
+        self.check_audio_rate()?;
+        let packet = match self.next_frame_index {
+            Some(expected) => {
+                if data.len() < DTX_HEADER_LEN {
+                    anyhow::bail!("frame too short for DTX header");
+                }
+                let (header, packet) = data.split_at(DTX_HEADER_LEN);
+                let index = u32::from_le_bytes(header.try_into()?);
+                // frames skipped by DTX were silence, fill in the gap,
+                // but for those reported lost, concealed below instead
+                let gap_frames = dtx_gap_frames(expected, index)?;
+                let (silent_frames, lost_frames) =
+                    split_dtx_gap(gap_frames, self.lost_frames);
+                self.lost_frames = lost_frames;
+                if silent_frames > 0 {
+                    let gap = silent_frames as usize * self.last_frame_samples;
+                    self.append_silence(gap);
+                }
+                self.next_frame_index = Some(next_dtx_index(index)?);
+                packet
+            }
+            None => data,
+        };
+        if self.lost_frames > 0 {
+            self.conceal_lost_frames(packet)?;
+        }
+        // any standard frame duration, 2.5ms to 120ms
+        let mut output = vec![0i16; MAX_OPUS_FRAME_SAMPLES];
         let samples_decoded =
             self.opus_decoder.decode(packet, &mut output, false)?;
-        if samples_decoded != (FRAME_SIZE_SAMPLES as usize) {
-            anyhow::bail!("decompressed to unexpected len {}", samples_decoded);
+        if samples_decoded == 0 {
+            anyhow::bail!("packet decompressed to no audio");
+        }
+        self.last_frame_samples = samples_decoded;
+        self.append(&output[..samples_decoded]);
+        Ok(())
+    }
+
+    /// Records Opus packets lost in transit, concealed when the next one
+    /// arrives, see `conceal_lost_frames`.
+    pub fn packets_lost(&mut self, count: u32) -> Result<()> {
+        if self.pcm {
+            anyhow::bail!("packet loss can only be concealed with Opus");
         }
-        self.accumulated_audio.extend(&output); // see advance for draining
-        self.vad.consume(&output);
+        let lost = self.lost_frames.saturating_add(count);
+        if lost > MAX_DTX_GAP_FRAMES {
+            anyhow::bail!("loss of {} frames too long", lost);
+        }
+        self.lost_frames = lost;
+        Ok(())
+    }
+
+    // Synthesizes the lost frames preceding `packet`: the last one from
+    // the in-band FEC data of `packet`, if the client encoded any, the
+    // ones before it with the decoder's packet loss concealment.
+    fn conceal_lost_frames(&mut self, packet: &[u8]) -> Result<()> {
+        let lost = std::mem::take(&mut self.lost_frames);
+        let mut output = vec![0i16; self.last_frame_samples];
+        for _ in 1..lost {
+            let n = self.opus_decoder.decode(&[], &mut output, false)?;
+            self.append(&output[..n]);
+        }
+        let n = self.opus_decoder.decode(packet, &mut output, true)?;
+        self.append(&output[..n]);
+        self.concealed_frames += lost as u64;
+        info!(
+            "concealed {} lost frames, {} in total",
+            lost, self.concealed_frames
+        );
         Ok(())
     }
 
+    /// Appends a frame of s16le PCM, carrying an odd trailing byte over to
+    /// the next frame.
+    pub fn append_pcm(&mut self, data: &[u8]) -> Result<()> {
+        if self.paused {
+            self.odd_byte = None; // the rest of the sample isn't coming
+            return Ok(());
+        }
+        self.check_audio_rate()?;
+        let mut bytes = Vec::with_capacity(data.len() + 1);
+        bytes.extend(self.odd_byte.take());
+        bytes.extend_from_slice(data);
+        if bytes.len() % 2 == 1 {
+            self.odd_byte = bytes.pop();
+        }
+        let samples: Vec<i16> = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        self.append(&samples);
+        Ok(())
+    }
+
+    /// Whether the last decode produced no timestamp tokens at all, so that
+    /// its segment boundaries are whisper's fallback rather than predicted.
+    /// Decided per decode, as max_len wraps segments into pieces of which
+    /// only the last carries the closing timestamp.
+    fn decoded_without_timestamps(&self) -> bool {
+        let token_beg = self.ctx.token_beg();
+        let n_segments = self.whisper_state.full_n_segments();
+        !(0..n_segments)
+            .filter_map(|i| self.whisper_state.get_segment(i))
+            .any(|segment| {
+                (0..segment.n_tokens())
+                    .filter_map(|j| segment.get_token(j))
+                    .any(|token| token.token_id() >= token_beg)
+            })
+    }
+
+    /// Segment `i` of the last decode of `audio`, timed within the buffer.
+    /// None if it has no tokens or nothing of it is left after clamping.
+    fn decoded_segment(
+        &self,
+        i: c_int,
+        audio: &DecodedAudio,
+        is_final: bool,
+    ) -> Result<Option<DecodedSegment>> {
+        let Some(segment) = self.whisper_state.get_segment(i) else {
+            return Ok(None);
+        };
+        let eot_id = self.ctx.token_eot();
+
+        // extract token-level timing for precise merging
+        let mut tokens = Vec::new();
+        let mut text_bytes = Vec::new(); // see text below
+        let mut text_ends = Vec::new(); // of each token in text_bytes
+        let mut logprobs = Vec::new();
+        for j in 0..segment.n_tokens() {
+            let Some(token) = segment.get_token(j) else {
+                continue;
+            };
+            let token_data = token.token_data();
+            // do not trust tokens beyond the actual audio
+            let Some((start_cs, end_cs)) =
+                audio.token_span(token_data.t0, token_data.t1)
+            else {
+                continue;
+            };
+            if token.token_id() < eot_id {
+                text_bytes.extend_from_slice(token.to_bytes()?);
+                logprobs.push(token_data.plog);
+            }
+            tokens.push(Token {
+                text: token.to_str_lossy()?.to_string(),
+                id: token.token_id(),
+                special: token.token_id() >= eot_id,
+                start_cs,
+                end_cs,
+                probability: token.token_probability(),
+                speaker_id: self.speaker_at(start_cs, end_cs),
+            });
+            text_ends.push(text_bytes.len());
+        }
+        if tokens.is_empty() {
+            return Ok(None); // no meaningful tokens
+        }
+
+        let Some((start_cs, end_cs)) = segment_span(
+            segment.start_timestamp() + audio.base_cs,
+            (segment.end_timestamp() + audio.base_cs).min(audio.end_cs()),
+            &tokens,
+            self.advance_cs,
+            audio.single_segment,
+        ) else {
+            return Ok(None);
+        };
+        let end_vad_probability =
+            vad_probability_at(&self.vad, end_cs, self.advance_cs);
+        let no_speech_probability = segment.no_speech_probability();
+
+        let segment = Segment {
+            text: segment_text(&text_bytes),
+            start_cs,
+            end_cs,
+            words: tokens_to_words(&tokens),
+            speaker_id: majority_speaker(&tokens),
+            hallucinated: self.hallucinated(
+                &tokens,
+                no_speech_probability,
+                end_vad_probability,
+            ),
+            tokens,
+            fallback_segmentation: audio.fallback_segmentation,
+            end_vad_probability,
+            no_speech_probability,
+            avg_logprob: avg_logprob(&logprobs),
+            is_final,
+            vad_whisper_disagreement: self.vad_whisper_disagreement(
+                no_speech_probability,
+                end_vad_probability,
+            ),
+            timestamp_unreliable: timestamp_unreliable(start_cs, end_cs),
+            start_tc: self.timecode(start_cs),
+            end_tc: self.timecode(end_cs),
+            seq: None, // see assign_seqs
+        };
+        Ok(Some(DecodedSegment {
+            segment,
+            text_bytes,
+            text_ends,
+        }))
+    }
+
+    /// Drops the trailing text tokens of a preview segment less likely
+    /// than min_token_prob, guesses that tend to flip between
+    /// transcriptions. None if no text is left. avg_logprob is kept.
+    /// `text_ends` are the ends of the tokens' bytes in `text_bytes`.
+    fn trim_unlikely_tail(
+        &self,
+        mut segment: Segment,
+        text_bytes: &[u8],
+        text_ends: &[usize],
+    ) -> Option<Segment> {
+        let min_prob = self.opts.min_token_prob;
+        match segment.tokens.iter().rposition(|t| !t.special) {
+            Some(i) if segment.tokens[i].probability < min_prob => {}
+            _ => return Some(segment), // nothing to trim
+        }
+        let last = segment
+            .tokens
+            .iter()
+            .rposition(|t| !t.special && t.probability >= min_prob)?;
+        segment.tokens.truncate(last + 1);
+        // the kept bytes decoded at once, like the whole text was
+        let kept = &text_bytes[..text_ends[last]];
+        segment.text = segment_text(kept);
+        segment.end_cs = segment.tokens[last].end_cs.max(segment.start_cs);
+        segment.end_tc = self.timecode(segment.end_cs);
+        segment.words = tokens_to_words(&segment.tokens);
+        segment.speaker_id = majority_speaker(&segment.tokens);
+        segment.end_vad_probability =
+            vad_probability_at(&self.vad, segment.end_cs, self.advance_cs);
+        segment.vad_whisper_disagreement = self.vad_whisper_disagreement(
+            segment.no_speech_probability,
+            segment.end_vad_probability,
+        );
+        segment.timestamp_unreliable =
+            timestamp_unreliable(segment.start_cs, segment.end_cs);
+        Some(segment)
+    }
+
+    fn hallucinated(
+        &self,
+        tokens: &[Token],
+        no_speech_probability: f32,
+        end_vad_probability: f32,
+    ) -> bool {
+        hallucinated(
+            tokens,
+            no_speech_probability,
+            end_vad_probability,
+            self.opts.hallucination_repeats,
+            self.opts.hallucination_no_speech,
+        )
+    }
+
+    fn vad_whisper_disagreement(
+        &self,
+        no_speech_probability: f32,
+        end_vad_probability: f32,
+    ) -> bool {
+        vad_whisper_disagreement(
+            no_speech_probability,
+            end_vad_probability,
+            self.disagreement_margin,
+        )
+    }
+
+    /// Rejects clients sending audio faster than `max_audio_rate` x realtime
+    /// for longer than a short burst.
+    fn check_audio_rate(&mut self) -> Result<()> {
+        let Some(max_rate) = self.opts.max_audio_rate else {
+            return Ok(());
+        };
+        let started = *self.audio_started.get_or_insert_with(Instant::now);
+        if too_fast(self.received_samples, started.elapsed(), max_rate) {
+            anyhow::bail!(
+                "audio arriving faster than {:.1}x realtime",
+                max_rate
+            );
+        }
+        Ok(())
+    }
+
+    fn assign_seqs(&mut self, segments: &mut [Segment]) {
+        if let Some(ref mut seqs) = self.seqs {
+            seqs.assign(segments);
+        }
+    }
+
+    fn min_samples(&self) -> usize {
+        self.opts.min_audio_cs as usize * CS_SAMPLES as usize
+    }
+
+    fn timecode(&self, cs: i64) -> Option<String> {
+        self.formatted_timecodes.then(|| format_timecode(cs))
+    }
+
+    fn append_silence(&mut self, n_samples: usize) {
+        self.append(&vec![0i16; n_samples]);
+    }
+
+    fn append(&mut self, samples: &[i16]) {
+        self.received_samples += samples.len() as u64;
+        let samples = match self.auto_gain {
+            Some(ref mut gain) => {
+                let mut gained = samples.to_vec();
+                // adapts only after speech, so that noise is not amplified
+                gain.process(&mut gained, self.vad.end_p() >= SPEECH_ENTER);
+                Cow::Owned(gained)
+            }
+            None => Cow::Borrowed(samples),
+        };
+        // the VAD hears what whisper does, quiet speech included, in order
+        debug_assert_eq!(self.vad.end_samples(), self.accumulated_audio.len());
+        self.vad.consume(&samples);
+        if let Some(ref mut echo) = self.echo {
+            echo.extend(s16le(&samples));
+        }
+        if let Some(ref mut dump) = self.audio_dump
+            && let Err(e) = dump.write(&samples)
+        {
+            error!("audio dump error, no longer dumping: {}", e);
+            self.audio_dump = None;
+        }
+        self.accumulated_audio.extend(samples.iter()); // see advance for draining
+    }
+
+    /// Decoded audio appended since the last call, as s16le PCM bytes,
+    /// if audio echo is enabled.
+    pub fn take_echo(&mut self) -> Option<Vec<u8>> {
+        let echo = self.echo.as_mut().filter(|e| !e.is_empty())?;
+        Some(std::mem::take(echo))
+    }
+
+    /// Words of a Transcription that start after the last word reported,
+    /// i.e. the growth of the hypothesis. Earlier words are never retracted.
+    pub fn words_appended(
+        &mut self,
+        msg: &ServerMessage,
+    ) -> Option<ServerMessage> {
+        let words = words_starting_from(msg, self.words_emitted_up_to_cs);
+        self.words_emitted_up_to_cs = words.last()?.end_cs;
+        Some(ServerMessage::WordsAppended { words })
+    }
+
+    /// How sure the model is about the newest words of a preview, None
+    /// without an incomplete segment with text.
+    pub fn uncertainty(msg: &ServerMessage) -> Option<ServerMessage> {
+        let ServerMessage::Transcription {
+            incomplete: Some(incomplete),
+            ..
+        } = msg
+        else {
+            return None;
+        };
+        let trailing: Vec<f32> = incomplete
+            .tokens
+            .iter()
+            .rev()
+            .filter(|t| !t.special)
+            .take(UNCERTAINTY_TOKENS)
+            .map(|t| t.probability)
+            .collect();
+        if trailing.is_empty() {
+            return None;
+        }
+        let value = trailing.iter().sum::<f32>() / trailing.len() as f32;
+        Some(ServerMessage::Uncertainty { value })
+    }
+
+    /// Overall quality of a final Transcription.
+    pub fn final_summary(&self, msg: &ServerMessage) -> Option<ServerMessage> {
+        let mut totals = self.summary.clone()?;
+        let ServerMessage::Transcription { complete, .. } = msg else {
+            return None;
+        };
+        complete.iter().for_each(|s| totals.add(s));
+        Some(totals.message())
+    }
+
+    /// Remembers the complete segments of a Transcription sent to the
+    /// client, so that an Advance can confirm them for the export and the
+    /// summary.
+    pub fn track_export(&mut self, msg: &ServerMessage) {
+        if self.confirmed.is_none() && self.summary.is_none() {
+            return;
+        }
+        if let ServerMessage::Transcription { complete, .. } = msg {
+            self.last_complete = complete.clone();
+        }
+    }
+
+    /// The whole session transcript: the confirmed segments followed by
+    /// those of the final Transcription `msg`.
+    pub fn session_export(
+        &self,
+        msg: &ServerMessage,
+        model: String,
+    ) -> Option<ServerMessage> {
+        let confirmed = self.confirmed.as_ref()?;
+        let ServerMessage::Transcription { complete, .. } = msg else {
+            return None;
+        };
+        let language = self.language.clone().or_else(|| {
+            let id = self.whisper_state.full_lang_id_from_state();
+            whisper_rs::get_lang_str(id).map(str::to_string)
+        });
+        Some(ServerMessage::SessionExport {
+            segments: confirmed.iter().chain(complete).cloned().collect(),
+            language,
+            duration_cs: self.advance_cs
+                + (self.accumulated_audio.len() as i64 * 100)
+                    / SAMPLE_RATE as i64,
+            model,
+        })
+    }
+
     pub fn advance(
         &mut self,
         timestamp: i64,
@@ -115,31 +975,105 @@ impl Session {
         // use client-provided context segment for prompt tokens (keep tail)
         self.prompt_tokens.clear();
         if let Some(segment) = context {
-            let from = segment.tokens.len().saturating_sub(MAX_PROMPT_TOKENS);
-            let token_ids = segment.tokens[from..].iter().map(|t| t.id);
-            self.prompt_tokens.extend(token_ids);
+            if let Some(text) = untokenized_text(&segment) {
+                let token_ids = self.ctx.tokenize(text, text.len() + 1)?;
+                let from = token_ids.len().saturating_sub(MAX_PROMPT_TOKENS);
+                self.prompt_tokens.extend(&token_ids[from..]);
+            } else {
+                let from =
+                    segment.tokens.len().saturating_sub(MAX_PROMPT_TOKENS);
+                let token_ids = segment.tokens[from..].iter().map(|t| t.id);
+                self.prompt_tokens.extend(token_ids);
+            }
         }
 
         self.accumulated_audio.drain(0..drop_samples);
         self.advance_cs = timestamp;
+        self.speaker_hints.retain(|&(_, end, _)| end > timestamp);
         self.advanced_since = true; // force retranscription
-        self.vad.reset(); // and recalculate VAD from remaining audio
-        self.vad.consume(&self.accumulated_audio);
+        self.window_boundary = None; // recomputed by the next transcription
+        // whatever the client advanced past is confirmed for the export
+        // and the summary
+        let last_complete = std::mem::take(&mut self.last_complete);
+        let newly_confirmed = confirmed_by(last_complete, timestamp);
+        if let Some(ref mut summary) = self.summary {
+            newly_confirmed.iter().for_each(|s| summary.add(s));
+        }
+        if let Some(ref mut confirmed) = self.confirmed {
+            confirmed.extend(newly_confirmed);
+        }
+        if let Some(ref mut seqs) = self.seqs {
+            seqs.advance(timestamp);
+        }
+        self.vad.drop_front(drop_cs); // the rest keeps its probabilities
 
         Ok(())
     }
 
+    /// Whether the end of `segment` is a safe place to auto-advance to:
+    /// reliably timed, confidently recognized and not inside speech.
+    fn is_window_boundary(&self, segment: &Segment) -> bool {
+        segment.end_cs > self.advance_cs
+            && !segment.fallback_segmentation
+            && !segment.timestamp_unreliable
+            && segment.confidence().is_some_and(|c| c >= LOW_CONFIDENCE)
+            && !self.vad.is_speech_at_cs(segment.end_cs - self.advance_cs)
+    }
+
+    /// Advances to the last window boundary of the previous transcription
+    /// for clients that never send Advance, keeping the buffer within
+    /// whisper's 30s context. The Transcription reports the new advance_cs.
+    /// Nothing happens without a boundary, speech is never cut.
+    fn auto_advance(&mut self) -> Result<()> {
+        let Some(segment) = self.window_boundary.take() else {
+            debug!("buffer over the window, but no boundary to advance to");
+            return Ok(());
+        };
+        let timestamp = segment.end_cs;
+        info!("auto-advancing to {:.2}s", timestamp as f64 / 100.);
+        self.advance(timestamp, Some(segment))
+    }
+
     pub fn transcribe(
         &mut self,
         is_final: bool,
     ) -> Result<Option<ServerMessage>> {
-        if self.accumulated_audio.len() < MIN_SAMPLES {
-            return Ok(None);
+        if is_final {
+            self.vad.flush(); // for end_vad_probability at the very end
+        }
+        let window_cs =
+            (self.accumulated_audio.len() / CS_SAMPLES as usize) as i64;
+        if window_cs > self.opts.max_window_cs {
+            self.auto_advance()?;
         }
-
         let current_end_cs = self.advance_cs
             + (self.accumulated_audio.len() as i64 * 100) / SAMPLE_RATE as i64;
 
+        let too_short = if self.min_speech {
+            self.vad.speech_cs() < self.opts.min_audio_cs
+        } else {
+            self.accumulated_audio.len() < self.min_samples()
+        };
+        if too_short {
+            match TooShort::new(self.advanced_since, is_final) {
+                TooShort::Skip => return Ok(None),
+                TooShort::Defer => {
+                    debug!("retranscription after advance deferred, too short");
+                    return Ok(None);
+                }
+                TooShort::Clear => {}
+            }
+            info!("finalizing after an advance with too little audio left");
+            self.advanced_since = false;
+            self.transcribed_up_to_cs = current_end_cs;
+            return Ok(Some(ServerMessage::Transcription {
+                complete: Vec::new(),
+                incomplete: None,
+                fast_preview: None,
+                advance_cs: self.advance_cs,
+            }));
+        }
+
         if !is_final
             && !self.advanced_since
             && current_end_cs == self.transcribed_up_to_cs
@@ -155,43 +1089,34 @@ impl Session {
             );
         }
 
-        let audio_f32: Vec<f32> = self
-            .accumulated_audio
-            .iter()
-            .map(|&s| s as f32 / 32768.0)
-            .collect();
+        // whisper hallucinates on leading silence, start shortly before speech
+        let trim_samples = if self.trim_leading_silence {
+            let onset = shared_vad::trim_leading_silence(
+                self.accumulated_audio.make_contiguous(),
+                &self.vad,
+                SPEECH_ENTER,
+            );
+            onset - onset % CS_SAMPLES as usize
+        } else {
+            0
+        };
+        let base_cs =
+            self.advance_cs + (trim_samples / CS_SAMPLES as usize) as i64;
 
-        let mut params = FullParams::new(self.sampling_strategy.clone());
-        params.set_language(self.language.as_deref()); // None = auto-detect
-        params.set_suppress_nst(true);
-        params.set_max_len(self.max_len);
-        params.set_max_tokens(self.max_tokens);
-        params.set_max_initial_ts(self.max_initial_ts);
-        params.set_single_segment(self.single_segment);
-        params.set_print_progress(false);
-        params.set_print_special(false);
-        params.set_print_realtime(false);
-        params.set_token_timestamps(true); // token-level timing
-        params.set_tokens(&self.prompt_tokens);
-        params.set_no_context(true);
+        let mut audio_f32 = std::mem::take(&mut self.audio_f32_scratch);
+        audio_f32.clear();
+        audio_f32.extend(
+            self.accumulated_audio
+                .range(trim_samples..)
+                .map(|&s| s as f32 / 32768.0),
+        );
 
-        if let Some(v) = self.opts.temperature_inc {
-            params.set_temperature_inc(v);
-        }
-        if let Some(v) = self.opts.entropy_thold {
-            params.set_entropy_thold(v);
-        }
-        if self.opts.dynamic_audio_ctx {
-            // scale audio_ctx to buffer length, multiple of 64, min 384
-            let needed =
-                (audio_f32.len() as i32 * 1500) / (SAMPLE_RATE as i32 * 30);
-            let aligned = ((needed + 63) / 64) * 64;
-            params.set_audio_ctx(aligned.max(384));
-        }
+        let prompt = build_prompt(&self.context_tokens, &self.prompt_tokens);
 
-        if let Some(ref prompt) = self.context {
-            params.set_initial_prompt(prompt);
-        }
+        self.last_audio_ctx = self.audio_ctx_for(audio_f32.len());
+        let mut params = self.full_params(self.language.as_deref(), &prompt);
+        params.set_max_initial_ts(self.max_initial_ts);
+        params.set_single_segment(self.single_segment);
 
         if self.opts.reinit_state {
             self.whisper_state = self.ctx.create_state()?;
@@ -200,6 +1125,8 @@ impl Session {
         let start = Instant::now();
         self.whisper_state.full(params, &audio_f32)?;
         let duration = start.elapsed().as_secs_f64();
+        let n_samples = audio_f32.len();
+        self.audio_f32_scratch = audio_f32; // reused by the next call
 
         self.transcribed_up_to_cs = current_end_cs;
         self.advanced_since = false;
@@ -214,86 +1141,63 @@ impl Session {
             duration,
             realtime_factor
         );
+        self.track_realtime_factor(realtime_factor);
+        METRICS.transcribed(realtime_factor);
 
+        let audio = DecodedAudio {
+            base_cs,
+            len_cs: (n_samples as i64 * 100) / SAMPLE_RATE as i64,
+            single_segment: self.single_segment,
+            fallback_segmentation: self.decoded_without_timestamps(),
+        };
         let n_segments = self.whisper_state.full_n_segments();
-        let eot_id = self.ctx.token_eot();
-
         let mut complete = Vec::new();
         let mut incomplete = None;
 
         for i in 0..n_segments {
-            let Some(segment) = self.whisper_state.get_segment(i) else {
+            let last = i == n_segments - 1;
+            let Some(DecodedSegment {
+                segment,
+                text_bytes,
+                text_ends,
+            }) = self.decoded_segment(i, &audio, !last || is_final)?
+            else {
                 continue;
             };
-
-            // add advance_cs for absolute "connection" time
-            let start_time = segment.start_timestamp() + self.advance_cs;
-
-            // extract token-level timing for precise merging
-            let mut tokens = Vec::new();
-            let n_tokens = segment.n_tokens();
-            let buffer_len_cs = (self.accumulated_audio.len() as i64 * 100)
-                / SAMPLE_RATE as i64;
-            for j in 0..n_tokens {
-                if let Some(token) = segment.get_token(j) {
-                    let token_text = token.to_str_lossy()?.to_string();
-                    let token_data = token.token_data();
-                    // do not trust tokens beyond the actual audio
-                    if token_data.t0 >= buffer_len_cs {
-                        continue;
-                    }
-                    // token timestamps are relative to buffer start
-                    tokens.push(shared_protocol::Token {
-                        text: token_text,
-                        id: token.token_id(),
-                        special: token.token_id() >= eot_id,
-                        start_cs: token_data.t0 + self.advance_cs,
-                        end_cs: token_data.t1 + self.advance_cs,
-                        probability: token.token_probability(),
-                    });
-                }
-            }
-
-            if tokens.is_empty() {
-                continue; // skip segments with no meaningful tokens
+            if no_speech(&segment, self.opts.no_speech_thold) {
+                continue;
             }
 
-            let segment_text = tokens
-                .iter()
-                .filter(|t| !t.special)
-                .map(|t| t.text.as_str())
-                .collect::<String>()
-                .trim()
-                .to_string();
-            let end_time =
-                (segment.end_timestamp() + self.advance_cs).min(current_end_cs);
-
-            let fallback_segmentation = (end_time - start_time) % 100 == 0;
-            let end_vad_probability =
-                self.vad.probability_at_cs(end_time - self.advance_cs);
-            let no_speech_probability = segment.no_speech_probability();
-
-            let segment = shared_protocol::Segment {
-                text: segment_text,
-                start_cs: start_time,
-                end_cs: end_time,
-                tokens,
-                fallback_segmentation,
-                end_vad_probability,
-                no_speech_probability,
-            };
-
-            if i < n_segments - 1 {
+            if !last {
                 complete.push(segment); // not last - always complete
             } else {
                 if is_final {
-                    complete.push(segment); // last - complete if finalizing
+                    if self.drop_low_quality_final && low_quality(&segment) {
+                        info!("dropping low quality final segment");
+                    } else {
+                        complete.push(segment); // complete if finalizing
+                    }
                 } else {
-                    incomplete = Some(segment); // incomplete otherwise
+                    // incomplete otherwise, without its flickering tail
+                    incomplete = self.trim_unlikely_tail(
+                        segment,
+                        &text_bytes,
+                        &text_ends,
+                    );
                 }
             }
         }
 
+        if let Some(granularity) = self.granularity {
+            complete = granularity.merge(complete);
+        }
+        self.assign_seqs(&mut complete);
+        self.window_boundary = complete
+            .iter()
+            .rev()
+            .find(|s| self.is_window_boundary(s))
+            .cloned();
+
         // return all segments (client filters based on advance_cs)
         Ok(Some(ServerMessage::Transcription {
             complete,
@@ -311,131 +1215,1083 @@ impl Session {
         from_cs: i64,
         is_final: bool,
     ) -> Result<Vec<shared_protocol::Segment>> {
-        let offset_samples =
-            ((from_cs - self.advance_cs) as usize) * (CS_SAMPLES as usize);
-        if offset_samples >= self.accumulated_audio.len() {
-            return Ok(Vec::new());
+        let mut segments = self.transcribe_range(from_cs, None, is_final)?;
+        self.assign_seqs(&mut segments);
+        Ok(segments)
+    }
+
+    /// A Transcription carrying only a fast_preview: the last few seconds
+    /// decoded greedily into a single segment with a tiny audio_ctx.
+    /// Less accurate than the full transcription, but ready much sooner.
+    /// None if disabled or the buffer is too short.
+    pub fn fast_preview(&mut self) -> Result<Option<ServerMessage>> {
+        if !self.fast_preview {
+            return Ok(None);
+        }
+        let audio = std::mem::take(&mut self.audio_f32_scratch);
+        let job = self.preview_job(audio);
+        let preview = job.decode(&self.ctx, &mut self.whisper_state);
+        self.audio_f32_scratch = job.audio; // reused by the next call
+        preview
+    }
+
+    /// The last few seconds of the buffer, written into `audio`, for a fast
+    /// preview decoded by this session's model or by a separate one.
+    pub fn preview_job(&self, mut audio: Vec<f32>) -> PreviewJob {
+        let (offset, start_cs, end_cs) =
+            preview_tail(self.accumulated_audio.len(), self.advance_cs);
+        audio.clear();
+        audio.extend(
+            self.accumulated_audio
+                .range(offset..)
+                .map(|&s| s as f32 / 32768.0),
+        );
+        PreviewJob {
+            audio,
+            start_cs,
+            end_cs,
+            advance_cs: self.advance_cs,
+            language: self.language.clone(),
+            translate: self.translate,
+            hallucination_repeats: self.opts.hallucination_repeats,
+            hallucination_no_speech: self.opts.hallucination_no_speech,
+            min_samples: self.min_samples(),
+            end_vad_probability: vad_probability_at(
+                &self.vad,
+                end_cs,
+                self.advance_cs,
+            ),
+            speaker_hints: self
+                .speaker_hints
+                .iter()
+                .filter(|&&(_, end, _)| end > start_cs)
+                .copied()
+                .collect(),
+            disagreement_margin: self.disagreement_margin,
+            formatted_timecodes: self.formatted_timecodes,
+            best_of: self.opts.preview_best_of,
+        }
+    }
+
+    /// Transcribe the whole buffer in overlapping windows, stitching the
+    /// results together; see `longform` for the window parameters.
+    /// Falls back to a regular final transcription for short buffers.
+    pub fn transcribe_long_form(&mut self) -> Result<Option<ServerMessage>> {
+        self.vad.flush();
+        let current_end_cs = self.advance_cs
+            + (self.accumulated_audio.len() as i64 * 100) / SAMPLE_RATE as i64;
+        if current_end_cs - self.advance_cs <= longform::WINDOW_CS {
+            return self.transcribe(true);
+        }
+
+        let mut complete = Vec::new();
+        let mut from_cs = self.advance_cs;
+        loop {
+            if self.abort.load(Ordering::Relaxed) {
+                anyhow::bail!("long-form transcription aborted");
+            }
+            let to_cs = (from_cs + longform::WINDOW_CS).min(current_end_cs);
+            let window = self.transcribe_range(from_cs, Some(to_cs), true)?;
+            longform::stitch(&mut complete, window, from_cs);
+            if to_cs == current_end_cs {
+                break;
+            }
+            from_cs = to_cs - longform::OVERLAP_CS;
         }
-        let audio_slice = &self.accumulated_audio[offset_samples..];
-        if audio_slice.len() < MIN_SAMPLES {
+        self.assign_seqs(&mut complete);
+        self.transcribed_up_to_cs = current_end_cs;
+        self.advanced_since = false;
+
+        Ok(Some(ServerMessage::Transcription {
+            complete,
+            incomplete: None,
+            fast_preview: None,
+            advance_cs: self.advance_cs,
+        }))
+    }
+
+    /// Transcribe audio from `from_cs` to `to_cs` (absolute, None = buffer
+    /// end), same as `transcribe_from`.
+    fn transcribe_range(
+        &mut self,
+        from_cs: i64,
+        to_cs: Option<i64>,
+        is_final: bool,
+    ) -> Result<Vec<shared_protocol::Segment>> {
+        let Some((from_cs, samples)) = range_samples(
+            from_cs,
+            to_cs,
+            self.advance_cs,
+            self.accumulated_audio.len(),
+        ) else {
+            return Ok(Vec::new());
+        };
+        if samples.len() < self.min_samples() {
             return Ok(Vec::new());
         }
 
-        let audio_f32: Vec<f32> =
-            audio_slice.iter().map(|&s| s as f32 / 32768.0).collect();
+        let mut audio_f32 = std::mem::take(&mut self.audio_f32_scratch);
+        audio_f32.clear();
+        audio_f32.extend(
+            self.accumulated_audio
+                .range(samples)
+                .map(|&s| s as f32 / 32768.0),
+        );
 
-        let mut params = FullParams::new(self.sampling_strategy.clone());
-        params.set_language(self.language.as_deref());
-        params.set_suppress_nst(true);
-        params.set_max_len(self.max_len);
-        params.set_max_tokens(self.max_tokens);
+        let prompt = build_prompt(&self.context_tokens, &[]); // mid-buffer
+        self.last_audio_ctx = self.audio_ctx_for(audio_f32.len());
+        let mut params = self.full_params(self.language.as_deref(), &prompt);
         params.set_max_initial_ts(0.0);
         params.set_single_segment(false);
-        params.set_print_progress(false);
         params.set_print_special(true);
-        params.set_print_realtime(false);
-        params.set_token_timestamps(true);
-        params.set_no_context(true);
-
-        if let Some(v) = self.opts.temperature_inc {
-            params.set_temperature_inc(v);
-        }
-        if let Some(v) = self.opts.entropy_thold {
-            params.set_entropy_thold(v);
-        }
-        if self.opts.dynamic_audio_ctx {
-            let needed =
-                (audio_f32.len() as i32 * 1500) / (SAMPLE_RATE as i32 * 30);
-            let aligned = ((needed + 63) / 64) * 64;
-            params.set_audio_ctx(aligned.max(384));
-        }
-
-        if let Some(ref prompt) = self.context {
-            params.set_initial_prompt(prompt);
-        }
 
         let start = Instant::now();
         self.whisper_state.full(params, &audio_f32)?;
         let duration = start.elapsed().as_secs_f64();
+        let n_samples = audio_f32.len();
+        self.audio_f32_scratch = audio_f32; // reused by the next call
 
-        let audio_duration = audio_slice.len() as f64 / SAMPLE_RATE as f64;
+        let audio_duration = n_samples as f64 / SAMPLE_RATE as f64;
         info!(
-            "two-stroke retranscription {:.2}s-end took {:.2}s at {:.2}x",
+            "retranscription {:.2}s-{:.2}s took {:.2}s at {:.2}x",
             from_cs as f64 / 100.,
+            (from_cs as f64 / 100.) + audio_duration,
             duration,
             audio_duration / duration,
         );
 
+        let audio = DecodedAudio {
+            base_cs: from_cs,
+            len_cs: (n_samples as i64 * 100) / SAMPLE_RATE as i64,
+            single_segment: false,
+            fallback_segmentation: self.decoded_without_timestamps(),
+        };
         let n_segments = self.whisper_state.full_n_segments();
-        let eot_id = self.ctx.token_eot();
-        let buffer_len_cs =
-            (audio_slice.len() as i64 * 100) / SAMPLE_RATE as i64;
         let mut segments = Vec::new();
 
         for i in 0..n_segments {
-            let Some(segment) = self.whisper_state.get_segment(i) else {
-                continue;
-            };
+            // the last one is included even if incomplete, for comparison
+            let is_final = i < n_segments - 1 || is_final;
+            if let Some(decoded) = self.decoded_segment(i, &audio, is_final)?
+                && !no_speech(&decoded.segment, self.opts.no_speech_thold)
+            {
+                segments.push(decoded.segment);
+            }
+        }
 
-            let start_time = segment.start_timestamp() + from_cs;
-            let mut tokens = Vec::new();
-            let n_tokens = segment.n_tokens();
-            for j in 0..n_tokens {
-                if let Some(token) = segment.get_token(j) {
-                    let token_text = token.to_str_lossy()?.to_string();
-                    let token_data = token.token_data();
-                    if token_data.t0 >= buffer_len_cs {
-                        continue;
-                    }
-                    tokens.push(shared_protocol::Token {
-                        text: token_text,
-                        id: token.token_id(),
-                        special: token.token_id() >= eot_id,
-                        start_cs: token_data.t0 + from_cs,
-                        end_cs: token_data.t1 + from_cs,
-                        probability: token.token_probability(),
-                    });
+        Ok(segments)
+    }
+}
+
+// Where the fast_preview tail, the last FAST_PREVIEW_CS of a buffer of
+// `buffer_len` starting at `advance_cs`, begins: as an offset into the
+// buffer, then as start_cs and end_cs.
+fn preview_tail(buffer_len: usize, advance_cs: i64) -> (usize, i64, i64) {
+    let tail_samples = FAST_PREVIEW_CS as usize * CS_SAMPLES as usize;
+    let offset = buffer_len.saturating_sub(tail_samples);
+    let start_cs = advance_cs + offset as i64 / CS_SAMPLES as i64;
+    let end_cs =
+        start_cs + ((buffer_len - offset) / CS_SAMPLES as usize) as i64;
+    (offset, start_cs, end_cs)
+}
+
+// Whether `segment` is dropped by --no-speech-thold.
+fn no_speech(segment: &shared_protocol::Segment, thold: f32) -> bool {
+    let drop = segment.no_speech_probability > thold;
+    if drop {
+        debug!("dropping segment {:?}, no speech", segment.text);
+    }
+    drop
+}
+
+// The VAD probability at `cs`, absolute like segment timestamps, while the
+// VAD only covers the buffer starting at `advance_cs`.
+fn vad_probability_at(vad: &Vad, cs: i64, advance_cs: i64) -> f32 {
+    vad.probability_at_cs(cs - advance_cs)
+}
+
+// The samples of a buffer of `buffer_len` starting at `advance_cs` that
+// from_cs..to_cs covers, with from_cs rebased to the buffer so that both
+// the slice and the timestamps start where the audio does. None if an
+// empty buffer or a range outside it leaves nothing to transcribe.
+fn range_samples(
+    from_cs: i64,
+    to_cs: Option<i64>,
+    advance_cs: i64,
+    buffer_len: usize,
+) -> Option<(i64, Range<usize>)> {
+    let from_cs = from_cs.max(advance_cs);
+    let to_samples =
+        |cs: i64| (cs - advance_cs).max(0) as usize * CS_SAMPLES as usize;
+    let start = to_samples(from_cs);
+    let end = to_cs.map_or(buffer_len, |cs| to_samples(cs).min(buffer_len));
+    (start < end).then_some((from_cs, start..end))
+}
+
+// a WAV file named after the session start, None if it can't be created
+fn create_dump(dir: &std::path::Path) -> Option<WavWriter> {
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let name = format!("{}-{}.wav", started, uuid::Uuid::new_v4());
+    let path = dir.join(name);
+    match WavWriter::create(&path) {
+        Ok(dump) => {
+            info!("Dumping audio to {}", path.display());
+            Some(dump)
+        }
+        Err(e) => {
+            error!("cannot create audio dump {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// The context first, then the carried tokens, the tail winning if the
+// prompt would be longer than MAX_PROMPT_TOKENS.
+fn build_prompt(context: &[c_int], carried: &[c_int]) -> Vec<c_int> {
+    let all = context.iter().chain(carried);
+    let n = context.len() + carried.len();
+    all.skip(n.saturating_sub(MAX_PROMPT_TOKENS))
+        .copied()
+        .collect()
+}
+
+// The requested initial decode temperature, within whisper's range.
+fn initial_temperature(config: &Config) -> Option<f32> {
+    config.temperature.map(|t| t.clamp(0., MAX_TEMPERATURE))
+}
+
+// Whether adaptive quality switches after `recent_rtf`: degrades once a
+// full window is slower than realtime, restores once it is comfortably
+// faster.
+fn quality_switch(recent_rtf: &VecDeque<f64>, degraded: bool) -> bool {
+    if recent_rtf.len() < QUALITY_WINDOW {
+        return false;
+    }
+    if degraded {
+        recent_rtf.iter().all(|&r| r > RESTORE_ABOVE_RTF)
+    } else {
+        recent_rtf.iter().all(|&r| r < DEGRADE_BELOW_RTF)
+    }
+}
+
+// Whether `received_samples` within `elapsed` exceed `max_rate` x realtime
+// by more than a burst.
+fn too_fast(received_samples: u64, elapsed: Duration, max_rate: f64) -> bool {
+    let received_s = received_samples as f64 / SAMPLE_RATE as f64;
+    received_s > elapsed.as_secs_f64() * max_rate + RATE_BURST_S
+}
+
+// The text of a context segment without usable tokens (e.g. a fallback
+// segment), to be tokenized for the prompt instead.
+fn untokenized_text(segment: &Segment) -> Option<&str> {
+    let text = segment.text.trim();
+    let tokenless = segment.tokens.iter().all(|t| t.special);
+    (tokenless && !text.is_empty()).then_some(text)
+}
+
+// the bytes of `samples` as s16le PCM
+fn s16le(samples: &[i16]) -> impl Iterator<Item = u8> + '_ {
+    samples.iter().flat_map(|s| s.to_le_bytes())
+}
+
+// start_cs..end_cs widened to cover the text tokens.
+fn envelope(start_cs: i64, end_cs: i64, tokens: &[Token]) -> (i64, i64) {
+    let mut words = tokens.iter().filter(|t| !t.special);
+    let Some(first) = words.next() else {
+        return (start_cs, end_cs);
+    };
+    let last = words.next_back().unwrap_or(first);
+    (start_cs.min(first.start_cs), end_cs.max(last.end_cs))
+}
+
+// Likely no speech, or recognized without confidence.
+fn low_quality(segment: &Segment) -> bool {
+    segment.no_speech_probability > FINAL_MAX_NO_SPEECH
+        || segment.confidence().is_none_or(|c| c < LOW_CONFIDENCE)
+}
+
+/// Where the audio of a decode lies in the session.
+struct DecodedAudio {
+    base_cs: i64, // absolute start, whisper's timestamps are relative to it
+    len_cs: i64,
+    single_segment: bool, // decoded into a single segment
+    fallback_segmentation: bool, // see decoded_without_timestamps
+}
+
+impl DecodedAudio {
+    fn end_cs(&self) -> i64 {
+        self.base_cs + self.len_cs
+    }
+
+    // absolute start and end of a token, None if it starts beyond the audio
+    fn token_span(&self, t0: i64, t1: i64) -> Option<(i64, i64)> {
+        if t0 >= self.len_cs {
+            return None;
+        }
+        Some((t0 + self.base_cs, t1.min(self.len_cs) + self.base_cs))
+    }
+}
+
+/// A segment of the last decode, with the bytes of its text tokens, see
+/// `Session::trim_unlikely_tail`.
+struct DecodedSegment {
+    segment: Segment,
+    text_bytes: Vec<u8>,
+    text_ends: Vec<usize>, // of each token in text_bytes
+}
+
+/// What transcribe does with a buffer too short to decode.
+#[derive(Debug, PartialEq)]
+enum TooShort {
+    Skip,  // nothing was taken back, wait for more audio
+    Defer, // advanced_since stays set, retranscribe once audio arrives
+    // the client still holds the pre-advance hypothesis, send an empty
+    // final Transcription rather than staying silent
+    Clear,
+}
+
+impl TooShort {
+    fn new(advanced_since: bool, is_final: bool) -> Self {
+        match (advanced_since, is_final) {
+            (false, _) => Self::Skip,
+            (true, false) => Self::Defer,
+            (true, true) => Self::Clear,
+        }
+    }
+}
+
+// start_cs..end_cs of a decoded segment: enveloping its tokens if it is the
+// only one, and never starting before the buffer, e.g. after a concurrent
+// advance. None if nothing is left.
+fn segment_span(
+    start_cs: i64,
+    end_cs: i64,
+    tokens: &[Token],
+    advance_cs: i64,
+    single_segment: bool,
+) -> Option<(i64, i64)> {
+    let (start_cs, end_cs) = if single_segment {
+        envelope(start_cs, end_cs, tokens)
+    } else {
+        (start_cs, end_cs)
+    };
+    let start_cs = start_cs.max(advance_cs);
+    (end_cs > start_cs).then_some((start_cs, end_cs))
+}
+
+// The complete segments of the last Transcription confirmed by an Advance
+// to `timestamp`: those it moved past entirely.
+fn confirmed_by(last_complete: Vec<Segment>, timestamp: i64) -> Vec<Segment> {
+    last_complete
+        .into_iter()
+        .filter(|s| s.end_cs <= timestamp)
+        .collect()
+}
+
+/// Numbers of complete segments issued so far, see Segment::seq.
+#[derive(Default)]
+struct SeqNumbers {
+    issued: Vec<(i64, u64)>, // (start_cs, seq)
+    next: u64,
+}
+
+impl SeqNumbers {
+    /// Numbers complete segments, reusing the number of a segment issued
+    /// before that starts at nearly the same time.
+    fn assign(&mut self, segments: &mut [Segment]) {
+        let mut used = Vec::new();
+        for segment in segments {
+            let known = self.issued.iter().find(|(start_cs, seq)| {
+                (start_cs - segment.start_cs).abs() <= SEQ_MATCH_CS
+                    && !used.contains(seq)
+            });
+            let seq = match known {
+                Some(&(_, seq)) => seq,
+                None => {
+                    let seq = self.next;
+                    self.next += 1;
+                    self.issued.push((segment.start_cs, seq));
+                    seq
                 }
+            };
+            used.push(seq);
+            segment.seq = Some(seq);
+        }
+    }
+
+    /// Forgets the segments no retranscription can match after an
+    /// advance to `timestamp`.
+    fn advance(&mut self, timestamp: i64) {
+        self.issued
+            .retain(|&(start_cs, _)| start_cs + SEQ_MATCH_CS >= timestamp);
+    }
+}
+
+/// Running totals of a FinalSummary, over the segments added so far.
+#[derive(Clone, Default)]
+struct SummaryTotals {
+    segments: usize,
+    confidence_sum: f32, // over the segments with text
+    with_text: usize,
+    low_confidence: usize,
+    span_cs: Option<(i64, i64)>, // first start, last end
+}
+
+impl SummaryTotals {
+    fn add(&mut self, segment: &Segment) {
+        self.segments += 1;
+        if let Some(confidence) = segment.confidence() {
+            self.confidence_sum += confidence;
+            self.with_text += 1;
+            if confidence < LOW_CONFIDENCE {
+                self.low_confidence += 1;
             }
+        }
+        let start_cs = self.span_cs.map_or(segment.start_cs, |(s, _)| s);
+        self.span_cs = Some((start_cs, segment.end_cs));
+    }
 
-            let segment_text = tokens
-                .iter()
-                .filter(|t| !t.special)
-                .map(|t| t.text.as_str())
-                .collect::<String>()
-                .trim()
-                .to_string();
-            let end_time = (segment.end_timestamp() + from_cs)
-                .min(from_cs + buffer_len_cs);
-
-            let fallback_segmentation = (end_time - start_time) % 100 == 0;
-            // VAD relative to from_cs offset within our slice
-            let vad_cs = end_time - from_cs;
-            let end_vad_probability = if vad_cs >= 0 && vad_cs < buffer_len_cs {
-                // Rebuild VAD for the slice
-                0.0 // no VAD for retranscription
-            } else {
-                0.0
-            };
-            let no_speech_probability = segment.no_speech_probability();
-
-            let seg = shared_protocol::Segment {
-                text: segment_text,
-                start_cs: start_time,
-                end_cs: end_time,
-                tokens,
-                fallback_segmentation,
-                end_vad_probability,
-                no_speech_probability,
-            };
+    fn message(&self) -> ServerMessage {
+        let mean_confidence = if self.with_text == 0 {
+            0.0
+        } else {
+            self.confidence_sum / self.with_text as f32
+        };
+        ServerMessage::FinalSummary {
+            total_segments: self.segments,
+            mean_confidence,
+            low_confidence_segments: self.low_confidence,
+            total_duration_cs: self.span_cs.map_or(0, |(s, e)| e - s),
+        }
+    }
+}
 
-            if i < n_segments - 1 || is_final {
-                segments.push(seg);
-            } else {
-                segments.push(seg); // include incomplete too for comparison
+// The `top_k` most probable languages, most probable first.
+fn rank_languages(
+    mut languages: Vec<(String, f32)>,
+    top_k: usize,
+) -> Vec<(String, f32)> {
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages.truncate(top_k);
+    languages
+}
+
+// Words of a Transcription starting at `from_cs` or later.
+fn words_starting_from(msg: &ServerMessage, from_cs: i64) -> Vec<Word> {
+    let ServerMessage::Transcription {
+        complete,
+        incomplete,
+        ..
+    } = msg
+    else {
+        return Vec::new();
+    };
+    complete
+        .iter()
+        .chain(incomplete.iter())
+        .flat_map(|s| tokens_to_words(&s.tokens))
+        .filter(|w| w.start_cs >= from_cs)
+        .collect()
+}
+
+// Frames skipped by DTX between the `expected` index and `index`.
+fn dtx_gap_frames(expected: u32, index: u32) -> Result<u32> {
+    if index < expected {
+        anyhow::bail!("frame {} out of order", index);
+    }
+    let gap_frames = index - expected;
+    if gap_frames > MAX_DTX_GAP_FRAMES {
+        anyhow::bail!("DTX gap of {} frames too long", gap_frames);
+    }
+    Ok(gap_frames)
+}
+
+// A DTX gap of `gap_frames` as the frames that were silence and the
+// `lost_frames` reported by PacketLost, the last ones of the gap; no more
+// can have been lost than the indices skipped.
+fn split_dtx_gap(gap_frames: u32, lost_frames: u32) -> (u32, u32) {
+    let lost_frames = lost_frames.min(gap_frames);
+    (gap_frames - lost_frames, lost_frames)
+}
+
+fn next_dtx_index(index: u32) -> Result<u32> {
+    match index.checked_add(1) {
+        Some(next) => Ok(next),
+        None => anyhow::bail!("frame index {} overflows", index),
+    }
+}
+
+// audio_ctx scaled to buffer length, multiple of 64, min 384
+fn scaled_audio_ctx(n_samples: usize) -> i32 {
+    let needed =
+        (n_samples as i32 * FULL_AUDIO_CTX) / (SAMPLE_RATE as i32 * 30);
+    let aligned = ((needed + 63) / 64) * 64;
+    aligned.max(384)
+}
+
+/// The text of a segment from the bytes of its text tokens, decoded all
+/// at once: a rare character can be split across byte-fallback tokens
+/// that are invalid UTF-8 alone.
+pub fn segment_text(text_bytes: &[u8]) -> String {
+    String::from_utf8_lossy(text_bytes).trim().to_string()
+}
+
+pub fn avg_logprob(logprobs: &[f32]) -> f32 {
+    if logprobs.is_empty() {
+        return 0.;
+    }
+    logprobs.iter().sum::<f32>() / logprobs.len() as f32
+}
+
+/// Speaker of the latest hint covering the midpoint of a token.
+pub fn hinted_speaker(
+    hints: &[(i64, i64, i32)], // (start_cs, end_cs, speaker_id)
+    start_cs: i64,
+    end_cs: i64,
+) -> Option<i32> {
+    let mid_cs = (start_cs + end_cs) / 2;
+    hints
+        .iter()
+        .rev()
+        .find(|&&(start, end, _)| start <= mid_cs && mid_cs < end)
+        .map(|&(_, _, speaker)| speaker)
+}
+
+/// Whether whisper's no-speech probability and the VAD's speech
+/// probability at the segment end tell different stories.
+pub fn vad_whisper_disagreement(
+    no_speech_probability: f32,
+    end_vad_probability: f32,
+    margin: f32,
+) -> bool {
+    let vad_no_speech = 1. - end_vad_probability;
+    (no_speech_probability - vad_no_speech).abs() > margin
+}
+
+/// Whether a segment looks made up, as whisper does on silence or music:
+/// some n-gram of two or more of its text tokens repeats back to back
+/// more than `max_repeats` times, or whisper and the VAD agree there is
+/// no speech.
+pub fn hallucinated(
+    tokens: &[Token],
+    no_speech_probability: f32,
+    end_vad_probability: f32,
+    max_repeats: usize,
+    max_no_speech: f32,
+) -> bool {
+    let no_speech = no_speech_probability > max_no_speech
+        && end_vad_probability < SPEECH_EXIT;
+    if no_speech {
+        return true;
+    }
+    if max_repeats == 0 {
+        return false;
+    }
+    let ids: Vec<c_int> =
+        tokens.iter().filter(|t| !t.special).map(|t| t.id).collect();
+    // from bigrams: "no no no no" is speech, a single token looping as
+    // long as max_repeats bigrams still counts
+    for n in 2..=MAX_LOOP_NGRAM {
+        // tokens continuing a period-n loop, n of them make a repeat
+        let mut run = 0;
+        for window in ids.windows(n + 1) {
+            run = if window[0] == window[n] { run + 1 } else { 0 };
+            if run / n + 1 > max_repeats {
+                return true;
             }
         }
+    }
+    false
+}
 
-        Ok(segments)
+// apply_logit_bias indexes the logits by these token IDs unchecked
+fn check_logit_bias(logit_bias: &[(i32, f32)], n_vocab: i32) -> Result<()> {
+    for &(id, bias) in logit_bias {
+        if id < 0 || id >= n_vocab {
+            anyhow::bail!("logit_bias token ID {} not in vocabulary", id);
+        }
+        if !bias.is_finite() {
+            anyhow::bail!("logit_bias for token {} is not finite", id);
+        }
+    }
+    Ok(())
+}
+
+/// Whisper logits filter adding the session's logit_bias, called before
+/// every sampled token. `user_data` points to the `Vec<(i32, f32)>`.
+unsafe extern "C" fn apply_logit_bias(
+    _ctx: *mut whisper_rs_sys::whisper_context,
+    _state: *mut whisper_rs_sys::whisper_state,
+    _tokens: *const whisper_rs_sys::whisper_token_data,
+    _n_tokens: c_int,
+    logits: *mut f32,
+    user_data: *mut c_void,
+) {
+    // SAFETY: user_data is set together with this callback in transcribe,
+    // token IDs were checked against the vocabulary in Session::new
+    let bias = unsafe { &*(user_data as *const Vec<(i32, f32)>) };
+    for &(id, b) in bias {
+        unsafe { *logits.add(id as usize) += b };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{segment, token, transcription};
+
+    const SECOND: usize = SAMPLE_RATE as usize;
+
+    #[test]
+    fn audio_ctx_is_scaled_and_aligned() {
+        assert_eq!(scaled_audio_ctx(0), 384);
+        assert_eq!(scaled_audio_ctx(SECOND), 384); // 50, raised to the min
+        assert_eq!(scaled_audio_ctx(10 * SECOND), 512); // 500
+        assert_eq!(scaled_audio_ctx(20 * SECOND), 1024); // 1000
+        for n_samples in (0..30 * SECOND).step_by(4321) {
+            let audio_ctx = scaled_audio_ctx(n_samples);
+            assert_eq!(audio_ctx % 64, 0);
+            let covered = audio_ctx as usize * 30 * SECOND;
+            assert!(covered >= n_samples * FULL_AUDIO_CTX as usize);
+        }
+    }
+
+    #[test]
+    fn dtx_gaps_are_counted_in_frames() {
+        assert_eq!(dtx_gap_frames(7, 7).unwrap(), 0);
+        assert_eq!(dtx_gap_frames(7, 10).unwrap(), 3);
+        assert!(dtx_gap_frames(7, 6).is_err()); // out of order
+        let too_long = 7 + MAX_DTX_GAP_FRAMES + 1;
+        assert!(dtx_gap_frames(7, too_long).is_err());
+    }
+
+    #[test]
+    fn lost_packets_are_concealed_instead_of_silent() {
+        assert_eq!(split_dtx_gap(5, 0), (5, 0));
+        assert_eq!(split_dtx_gap(5, 2), (3, 2));
+        assert_eq!(split_dtx_gap(2, 3), (0, 2)); // only 2 indices skipped
+        assert_eq!(split_dtx_gap(0, 1), (0, 0));
+    }
+
+    #[test]
+    fn last_dtx_index_does_not_wrap() {
+        assert_eq!(next_dtx_index(0).unwrap(), 1);
+        assert!(next_dtx_index(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn only_words_past_the_last_reported_are_appended() {
+        let msg = transcription(
+            vec![segment(vec![token(" Hello", 0, 40), token(",", 40, 45)])],
+            Some(segment(vec![token(" wor", 50, 70), token("ld", 70, 90)])),
+        );
+        let words = words_starting_from(&msg, 0);
+        let texts: Vec<&str> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(texts, ["Hello,", "world"]);
+        assert_eq!(words[1].end_cs, 90);
+        let words = words_starting_from(&msg, 45);
+        assert_eq!(words.len(), 1);
+        assert_eq!(words[0].start_cs, 50);
+        assert!(words_starting_from(&msg, 90).is_empty());
+    }
+
+    #[test]
+    fn most_probable_languages_are_ranked_first() {
+        let languages = [("en", 0.2), ("de", 0.7), ("fr", 0.05), ("ja", 0.05)]
+            .map(|(lang, p)| (lang.to_string(), p))
+            .to_vec();
+        let ranked = rank_languages(languages.clone(), 2);
+        assert_eq!(ranked, [("de".to_string(), 0.7), ("en".to_string(), 0.2)]);
+        assert_eq!(rank_languages(languages, 10).len(), 4);
+    }
+
+    #[test]
+    fn summary_totals_span_confirmed_and_final_segments() {
+        let mut quiet = token(" um", 1200, 1300);
+        quiet.probability = 0.2;
+        let mut totals = SummaryTotals::default();
+        totals.add(&segment(vec![token(" Hello", 100, 400)])); // confirmed
+        let mut final_totals = totals.clone();
+        final_totals.add(&segment(vec![quiet]));
+        let ServerMessage::FinalSummary {
+            total_segments,
+            mean_confidence,
+            low_confidence_segments,
+            total_duration_cs,
+        } = final_totals.message()
+        else {
+            unreachable!();
+        };
+        assert_eq!(total_segments, 2);
+        assert!((mean_confidence - 0.55).abs() < 1e-6);
+        assert_eq!(low_confidence_segments, 1);
+        assert_eq!(total_duration_cs, 1200);
+        // the running totals aren't affected by the final segments
+        assert_eq!(totals.segments, 1);
+    }
+
+    #[test]
+    fn empty_summary_has_zero_totals() {
+        let ServerMessage::FinalSummary {
+            total_segments,
+            mean_confidence,
+            total_duration_cs,
+            ..
+        } = SummaryTotals::default().message()
+        else {
+            unreachable!();
+        };
+        assert_eq!((total_segments, total_duration_cs), (0, 0));
+        assert_eq!(mean_confidence, 0.0);
+    }
+
+    #[test]
+    fn dubious_final_segments_are_low_quality() {
+        let good = segment(vec![token(" Hello", 0, 40)]);
+        assert!(!low_quality(&good));
+        let mut silent = good.clone();
+        silent.no_speech_probability = 0.8;
+        assert!(low_quality(&silent));
+        let mut unsure = good.clone();
+        unsure.tokens[0].probability = 0.3;
+        assert!(low_quality(&unsure));
+        let mut textless = good;
+        textless.tokens[0].special = true;
+        assert!(low_quality(&textless));
+    }
+
+    #[test]
+    fn single_segment_envelopes_its_text_tokens() {
+        let mut tokens = vec![
+            token(" One", 90, 120),
+            token(" two", 120, 180),
+            token(" three", 180, 260),
+        ];
+        assert_eq!(envelope(100, 250, &tokens), (90, 260));
+        assert_eq!(envelope(50, 300, &tokens), (50, 300));
+        tokens[0].special = true; // special tokens don't count
+        tokens[2].special = true;
+        assert_eq!(envelope(150, 160, &tokens), (120, 180));
+        assert_eq!(envelope(100, 250, &[]), (100, 250));
+    }
+
+    #[test]
+    fn echo_is_little_endian_pcm() {
+        let bytes: Vec<u8> = s16le(&[1, -2, i16::MAX, i16::MIN]).collect();
+        assert_eq!(bytes, [0x01, 0x00, 0xfe, 0xff, 0xff, 0x7f, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn tokenless_context_is_tokenized_from_its_text() {
+        let mut context = segment(vec![token(" Hello", 0, 40)]);
+        assert_eq!(untokenized_text(&context), None);
+        context.tokens[0].special = true;
+        assert_eq!(untokenized_text(&context), Some("Hello"));
+        context.tokens.clear();
+        assert_eq!(untokenized_text(&context), Some("Hello"));
+        context.text = " ".to_string();
+        assert_eq!(untokenized_text(&context), None);
+    }
+
+    #[test]
+    fn disagreement_needs_more_than_the_margin() {
+        // silent to both
+        assert!(!vad_whisper_disagreement(0.9, 0.1, 0.5));
+        // whisper hears nothing where the VAD hears speech, and vice versa
+        assert!(vad_whisper_disagreement(0.9, 0.9, 0.5));
+        assert!(vad_whisper_disagreement(0.05, 0.1, 0.5));
+        assert!(!vad_whisper_disagreement(0.05, 0.1, 0.9));
+    }
+
+    #[test]
+    fn audio_may_run_ahead_of_the_rate_by_a_burst() {
+        let second = SAMPLE_RATE as u64;
+        let minute = Duration::from_secs(60);
+        assert!(!too_fast(60 * second, minute, 1.0));
+        assert!(!too_fast(70 * second, minute, 1.0)); // a full burst ahead
+        assert!(too_fast(71 * second, minute, 1.0));
+        assert!(!too_fast(130 * second, minute, 2.0));
+        assert!(too_fast(11 * second, Duration::ZERO, 1.0));
+    }
+
+    #[test]
+    fn token_timestamps_are_clamped_to_the_decoded_audio() {
+        let audio = DecodedAudio {
+            base_cs: 500,
+            len_cs: 300,
+            single_segment: false,
+            fallback_segmentation: false,
+        };
+        assert_eq!(audio.token_span(10, 40), Some((510, 540)));
+        assert_eq!(audio.token_span(280, 320), Some((780, 800)));
+        assert_eq!(audio.token_span(300, 320), None);
+    }
+
+    #[test]
+    fn segments_never_start_before_the_advance() {
+        let tokens = [token(" late", 480, 560)];
+        // a raw start preceding the advance, e.g. after a concurrent one
+        assert_eq!(
+            segment_span(450, 600, &tokens, 500, false),
+            Some((500, 600))
+        );
+        // the envelope is clamped too
+        assert_eq!(
+            segment_span(520, 600, &tokens, 500, true),
+            Some((500, 600))
+        );
+        // nothing left after clamping
+        assert_eq!(segment_span(400, 500, &tokens, 500, false), None);
+        assert_eq!(segment_span(600, 600, &tokens, 500, false), None);
+    }
+
+    #[test]
+    fn initial_temperature_is_clamped() {
+        let with = |temperature| Config {
+            temperature,
+            ..Config::default()
+        };
+        assert_eq!(initial_temperature(&with(None)), None);
+        assert_eq!(initial_temperature(&with(Some(0.4))), Some(0.4));
+        assert_eq!(initial_temperature(&with(Some(-1.))), Some(0.));
+        assert_eq!(initial_temperature(&with(Some(3.))), Some(1.));
+    }
+
+    #[test]
+    fn prompt_keeps_its_tail_within_the_cap() {
+        assert_eq!(build_prompt(&[1, 2], &[3]), [1, 2, 3]);
+        assert!(build_prompt(&[], &[]).is_empty());
+        let context: Vec<c_int> = (0..300).collect();
+        let prompt = build_prompt(&context, &[]);
+        assert_eq!(prompt.len(), MAX_PROMPT_TOKENS);
+        assert_eq!(prompt[0], 300 - MAX_PROMPT_TOKENS as c_int);
+        let carried: Vec<c_int> = (1000..1100).collect();
+        let prompt = build_prompt(&context, &carried);
+        assert_eq!(prompt.len(), MAX_PROMPT_TOKENS);
+        assert_eq!(prompt[prompt.len() - 100..], carried[..]);
+    }
+
+    #[test]
+    fn too_short_buffers_clear_only_final_advances() {
+        assert_eq!(TooShort::new(false, false), TooShort::Skip);
+        assert_eq!(TooShort::new(false, true), TooShort::Skip);
+        assert_eq!(TooShort::new(true, false), TooShort::Defer);
+        assert_eq!(TooShort::new(true, true), TooShort::Clear);
+    }
+
+    #[test]
+    fn quality_switches_only_on_a_full_agreeing_window() {
+        let window = |rtf: &[f64]| rtf.iter().copied().collect();
+        assert!(!quality_switch(&window(&[0.5, 0.5]), false));
+        assert!(quality_switch(&window(&[0.5, 0.5, 0.9]), false));
+        assert!(!quality_switch(&window(&[0.5, 1.5, 0.9]), false));
+        // between the thresholds, either quality stays
+        assert!(!quality_switch(&window(&[1.5, 1.5, 1.5]), false));
+        assert!(!quality_switch(&window(&[1.5, 1.5, 1.5]), true));
+        assert!(quality_switch(&window(&[3., 4., 3.]), true));
+    }
+
+    #[test]
+    fn characters_split_across_tokens_are_decoded_whole() {
+        // " naïve" with the ï split between two byte-fallback tokens
+        let tokens: [&[u8]; 3] = [b" na", &[0xc3], &[0xaf, b'v', b'e']];
+        assert_eq!(segment_text(&tokens.concat()), "naïve");
+        // only a character cut off at the end is lost
+        assert_eq!(segment_text(&[b'a', 0xc3]), "a\u{fffd}");
+    }
+
+    #[test]
+    fn seqs_survive_retranscriptions() {
+        let at = |start_cs| segment(vec![token(" a", start_cs, start_cs + 50)]);
+        let seqs = |segments: &[Segment]| -> Vec<_> {
+            segments.iter().map(|s| s.seq.unwrap()).collect()
+        };
+        let mut numbers = SeqNumbers::default();
+        let mut segments = [at(0), at(300)];
+        numbers.assign(&mut segments);
+        assert_eq!(seqs(&segments), [0, 1]);
+        // shifted a little and split, the new one gets a new number
+        let mut segments = [at(20), at(40), at(330), at(600)];
+        numbers.assign(&mut segments);
+        assert_eq!(seqs(&segments), [0, 2, 1, 3]);
+        numbers.advance(400);
+        let mut segments = [at(330), at(600)];
+        numbers.assign(&mut segments);
+        assert_eq!(seqs(&segments), [4, 3]);
+    }
+
+    #[test]
+    fn advances_confirm_the_segments_they_move_past() {
+        let last_complete = vec![
+            segment(vec![token(" One", 0, 150)]),
+            segment(vec![token(" two", 150, 300)]),
+            segment(vec![token(" three", 300, 420)]),
+        ];
+        let confirmed = confirmed_by(last_complete, 300);
+        let texts: Vec<_> = confirmed.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, [" One", " two"]);
+    }
+
+    #[test]
+    fn uncertainty_averages_the_newest_text_tokens() {
+        let mut tokens: Vec<Token> = (0..7)
+            .map(|i| Token {
+                probability: if i < 2 { 0.1 } else { 0.6 },
+                ..token(" w", i * 10, i * 10 + 10)
+            })
+            .collect();
+        tokens.push(Token {
+            special: true,
+            probability: 0.,
+            ..token("[_TT_70]", 70, 70)
+        });
+        let preview = transcription(Vec::new(), Some(segment(tokens)));
+        let Some(ServerMessage::Uncertainty { value }) =
+            Session::uncertainty(&preview)
+        else {
+            panic!("no Uncertainty");
+        };
+        assert!((value - 0.6).abs() < 1e-6);
+        let finished = transcription(Vec::new(), None);
+        assert!(Session::uncertainty(&finished).is_none());
+    }
+
+    #[test]
+    fn logit_bias_is_added_to_the_biased_tokens() {
+        let bias = vec![(1, 2.5), (3, -100.)];
+        assert!(check_logit_bias(&bias, 4).is_ok());
+        let mut logits = [0.5f32; 4];
+        // SAFETY: user_data points to the bias, its IDs index logits
+        unsafe {
+            apply_logit_bias(
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                0,
+                logits.as_mut_ptr(),
+                &bias as *const _ as *mut c_void,
+            );
+        }
+        assert_eq!(logits, [0.5, 3., 0.5, -99.5]);
+    }
+
+    #[test]
+    fn logit_bias_outside_the_vocabulary_is_rejected() {
+        assert!(check_logit_bias(&[(4, 1.)], 4).is_err());
+        assert!(check_logit_bias(&[(-1, 1.)], 4).is_err());
+        assert!(check_logit_bias(&[(0, f32::NAN)], 4).is_err());
+    }
+
+    #[test]
+    fn ranges_are_rebased_to_the_buffer() {
+        let cs = CS_SAMPLES as usize;
+        // before the advance, the range starts with the buffer
+        assert_eq!(
+            range_samples(50, None, 100, 300 * cs),
+            Some((100, 0..300 * cs))
+        );
+        assert_eq!(
+            range_samples(150, Some(250), 100, 300 * cs),
+            Some((150, 50 * cs..150 * cs))
+        );
+        assert_eq!(
+            range_samples(150, Some(900), 100, 300 * cs),
+            Some((150, 50 * cs..300 * cs))
+        );
+        assert_eq!(range_samples(400, None, 100, 300 * cs), None);
+        assert_eq!(range_samples(150, Some(120), 100, 300 * cs), None);
+        assert_eq!(range_samples(0, None, 0, 0), None);
+    }
+
+    #[test]
+    fn previews_cover_the_buffer_tail() {
+        let cs = CS_SAMPLES as usize;
+        assert_eq!(preview_tail(100 * cs, 500), (0, 500, 600));
+        let long = (FAST_PREVIEW_CS as usize + 200) * cs;
+        assert_eq!(
+            preview_tail(long, 500),
+            (200 * cs, 700, 700 + FAST_PREVIEW_CS)
+        );
+        assert_eq!(preview_tail(0, 500), (0, 500, 500));
+    }
+
+    // scores 1cs frames by their first sample, in thousandths
+    struct Scripted;
+
+    impl shared_vad::VadBackend for Scripted {
+        fn predict_frame(&mut self, samples: &[i16]) -> f32 {
+            samples[0] as f32 / 1000.
+        }
+
+        fn frame_len(&self) -> usize {
+            CS_SAMPLES as usize
+        }
+    }
+
+    #[test]
+    fn retranscribed_segments_carry_the_vad_of_their_end() {
+        // a buffer advanced to 5s: a second of silence, then one of speech
+        let mut vad = Vad::with_backend(Box::new(Scripted));
+        vad.consume(&vec![0; 100 * CS_SAMPLES as usize]);
+        vad.consume(&vec![900; 100 * CS_SAMPLES as usize]);
+        assert_eq!(vad_probability_at(&vad, 650, 500), 0.9);
+        assert_eq!(vad_probability_at(&vad, 550, 500), 0.);
+    }
+
+    #[test]
+    fn near_silence_is_hallucinated_when_the_vad_agrees() {
+        let tokens: Vec<Token> = (0..4)
+            .map(|i| Token {
+                id: i,
+                ..token(" word", i as i64 * 10, i as i64 * 10 + 10)
+            })
+            .collect();
+        // whisper hears no speech, nor does the VAD
+        assert!(hallucinated(&tokens, 0.8, 0.1, 0, 0.6));
+        // the VAD does hear speech
+        assert!(!hallucinated(&tokens, 0.8, 0.9, 0, 0.6));
+        // whisper is not sure enough there is none
+        assert!(!hallucinated(&tokens, 0.5, 0.1, 0, 0.6));
+    }
+
+    #[test]
+    fn short_repetitions_are_speech() {
+        let repeated = |n| {
+            (0..n)
+                .map(|i| Token {
+                    id: 7,
+                    ..token(" no", i * 10, i * 10 + 10)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(!hallucinated(&repeated(4), 0., 1., 3, 0.6));
+        assert!(!hallucinated(&repeated(7), 0., 1., 3, 0.6));
+        assert!(hallucinated(&repeated(8), 0., 1., 3, 0.6)); // 4 bigrams
+    }
+
+    #[test]
+    fn looping_tokens_are_hallucinated() {
+        let tokens: Vec<Token> = [1, 2, 1, 2, 1, 2]
+            .into_iter()
+            .map(|id| Token {
+                id,
+                ..token(" la", 0, 10)
+            })
+            .collect();
+        assert!(!hallucinated(&tokens, 0., 1., 3, 0.6)); // 3 repeats allowed
+        assert!(hallucinated(&tokens, 0., 1., 2, 0.6));
+        assert!(!hallucinated(&tokens, 0., 1., 0, 0.6)); // check disabled
+    }
+
+    #[test]
+    fn no_speech_thold_drops_only_above_it() {
+        let mut quiet = segment(vec![token(" hm", 0, 10)]);
+        quiet.no_speech_probability = 0.7;
+        assert!(no_speech(&quiet, 0.6));
+        assert!(!no_speech(&quiet, 0.7));
+        assert!(!no_speech(&quiet, 1.)); // the default, drops nothing
     }
 }
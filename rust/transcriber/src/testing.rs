@@ -0,0 +1,49 @@
+use shared_protocol::{Segment, ServerMessage, Token};
+
+/// A text token, a leading space starting a new word.
+pub fn token(text: &str, start_cs: i64, end_cs: i64) -> Token {
+    Token {
+        text: text.to_string(),
+        id: 0,
+        special: false,
+        start_cs,
+        end_cs,
+        probability: 0.9,
+        speaker_id: None,
+    }
+}
+
+/// A segment spanning its tokens, which must not be empty.
+pub fn segment(tokens: Vec<Token>) -> Segment {
+    Segment {
+        text: tokens.iter().map(|t| t.text.as_str()).collect(),
+        start_cs: tokens[0].start_cs,
+        end_cs: tokens[tokens.len() - 1].end_cs,
+        tokens,
+        words: Vec::new(),
+        fallback_segmentation: false,
+        end_vad_probability: 0.,
+        no_speech_probability: 0.,
+        avg_logprob: 0.,
+        is_final: false,
+        vad_whisper_disagreement: false,
+        hallucinated: false,
+        timestamp_unreliable: false,
+        start_tc: None,
+        end_tc: None,
+        seq: None,
+        speaker_id: None,
+    }
+}
+
+pub fn transcription(
+    complete: Vec<Segment>,
+    incomplete: Option<Segment>,
+) -> ServerMessage {
+    ServerMessage::Transcription {
+        complete,
+        incomplete,
+        fast_preview: None,
+        advance_cs: 0,
+    }
+}
@@ -0,0 +1,27 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+/// A TLS acceptor for a PEM certificate chain and private key, for serving
+/// wss:// without a reverse proxy.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate in {}", cert_path.display());
+    }
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let Some(key) = rustls_pemfile::private_key(&mut reader)? else {
+        anyhow::bail!("no private key in {}", key_path.display());
+    };
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
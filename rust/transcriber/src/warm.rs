@@ -0,0 +1,84 @@
+use anyhow::Result;
+use shared_protocol::SAMPLE_RATE;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperState};
+
+/// Whisper states created, and optionally primed with a decode of silence,
+/// ahead of time, so that new sessions don't pay for it on their first
+/// transcription.
+pub struct WarmPool {
+    size: usize,
+    warm_up_samples: usize, // silence decoded to prime a state, 0 = none
+    states: Mutex<Vec<(Arc<WhisperContext>, WhisperState)>>,
+}
+
+impl WarmPool {
+    pub fn new(size: usize, warm_up_ms: u32) -> Self {
+        Self {
+            size,
+            warm_up_samples: ms_to_samples(warm_up_ms),
+            states: Mutex::new(Vec::with_capacity(size)),
+        }
+    }
+
+    /// A ready state for `ctx`, if any. States of a previously loaded model
+    /// are discarded.
+    pub fn take(&self, ctx: &Arc<WhisperContext>) -> Option<WhisperState> {
+        let mut states = self.states.lock().unwrap();
+        states.retain(|(c, _)| Arc::ptr_eq(c, ctx));
+        states.pop().map(|(_, state)| state)
+    }
+
+    /// Tops the pool up to its size in the background.
+    pub fn refill(self: &Arc<Self>, ctx: Arc<WhisperContext>) {
+        let pool = self.clone();
+        tokio::task::spawn_blocking(move || {
+            while pool.states.lock().unwrap().len() < pool.size {
+                let state = match pool.prepare(&ctx) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        error!("warm state preparation error: {}", e);
+                        return;
+                    }
+                };
+                let mut states = pool.states.lock().unwrap();
+                if states.len() >= pool.size {
+                    return; // filled up by a concurrent refill
+                }
+                states.push((ctx.clone(), state));
+                info!("warm states ready: {}/{}", states.len(), pool.size);
+            }
+        });
+    }
+
+    fn prepare(&self, ctx: &WhisperContext) -> Result<WhisperState> {
+        let mut state = ctx.create_state()?;
+        if self.warm_up_samples > 0 {
+            let mut params =
+                FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+            params.set_print_progress(false);
+            params.set_print_special(false);
+            params.set_print_realtime(false);
+            params.set_no_context(true);
+            state.full(params, &vec![0.0f32; self.warm_up_samples])?;
+        }
+        Ok(state)
+    }
+}
+
+fn ms_to_samples(ms: u32) -> usize {
+    ms as usize * SAMPLE_RATE as usize / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a pool can't be built here, dropping its states needs whisper
+    #[test]
+    fn warm_up_silence_is_sized_in_samples() {
+        assert_eq!(ms_to_samples(250), 4000);
+        assert_eq!(ms_to_samples(0), 0);
+    }
+}
@@ -0,0 +1,104 @@
+use shared_protocol::SAMPLE_RATE;
+use std::fs::File;
+use std::io::{BufWriter, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+use tracing::error;
+
+const HEADER_LEN: u32 = 44;
+
+/// A 16kHz mono s16le WAV file written as audio arrives. The sizes in the
+/// header are filled in on drop, until then they read as empty.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header(0))?;
+        Ok(Self {
+            file,
+            data_bytes: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> Result<()> {
+        for s in samples {
+            self.file.write_all(&s.to_le_bytes())?;
+        }
+        let bytes = (samples.len() * 2) as u32;
+        self.data_bytes = self.data_bytes.saturating_add(bytes);
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?; // flushes the buffer first
+        self.file.write_all(&header(self.data_bytes))?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            error!("error finalizing audio dump: {}", e);
+        }
+    }
+}
+
+fn header(data_bytes: u32) -> Vec<u8> {
+    let mut h = Vec::with_capacity(HEADER_LEN as usize);
+    h.extend_from_slice(b"RIFF");
+    let riff_len = (HEADER_LEN - 8).saturating_add(data_bytes);
+    h.extend_from_slice(&riff_len.to_le_bytes());
+    h.extend_from_slice(b"WAVEfmt ");
+    h.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk length
+    h.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    h.extend_from_slice(&1u16.to_le_bytes()); // mono
+    h.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    h.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // bytes per second
+    h.extend_from_slice(&2u16.to_le_bytes()); // bytes per sample
+    h.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    h.extend_from_slice(b"data");
+    h.extend_from_slice(&data_bytes.to_le_bytes());
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_16khz_mono_s16le() {
+        let h = header(3200);
+        assert_eq!(h.len(), HEADER_LEN as usize);
+        assert_eq!(&h[..4], b"RIFF");
+        assert_eq!(u32_at(&h, 4), 36 + 3200);
+        assert_eq!(&h[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&h, 24), 16000);
+        assert_eq!(u32_at(&h, 28), 32000);
+        assert_eq!(&h[36..40], b"data");
+        assert_eq!(u32_at(&h, 40), 3200);
+        // never wraps, however long the dump
+        assert_eq!(u32_at(&header(u32::MAX), 4), u32::MAX);
+    }
+
+    #[test]
+    fn sizes_are_filled_in_on_drop() {
+        let path = std::env::temp_dir()
+            .join(format!("wav-test-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path).unwrap();
+        wav.write(&[1, -1, 2]).unwrap();
+        drop(wav);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN as usize + 6);
+        assert_eq!(&bytes[..HEADER_LEN as usize], header(6));
+        assert_eq!(&bytes[HEADER_LEN as usize..], [1, 0, 255, 255, 2, 0]);
+    }
+}